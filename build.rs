@@ -7,11 +7,21 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also bundles every file under `web_app/` into the firmware. Each file is kept as is,
+//! plus a gzipped copy when that is smaller, and `assets.rs` is written to `OUT_DIR` with a
+//! route table the `assets` module includes. Each copy gets an ETag from a hash of the file
+//! so browsers can revalidate instead of downloading it again.
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const WEB_APP_DIR: &str = "web_app";
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -33,4 +43,137 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+
+    bundle_web_app(out);
+}
+
+fn bundle_web_app(out: &Path) {
+    println!("cargo:rerun-if-changed={WEB_APP_DIR}");
+
+    let mut files = Vec::new();
+    collect_files(Path::new(WEB_APP_DIR), &mut files);
+    files.sort();
+
+    let assets_dir = out.join("assets");
+    fs::create_dir_all(&assets_dir).unwrap();
+
+    let mut table = String::from("pub static ASSETS: &[Asset] = &[\n");
+    for (i, file) in files.iter().filter(|file| is_served(file)).enumerate() {
+        let relative = file.strip_prefix(WEB_APP_DIR).unwrap();
+        let contents = fs::read(file).unwrap();
+        let etag = format!("\"{:016x}\"", fnv1a(&contents));
+        let route = route(relative);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&contents).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let bundled = assets_dir.join(format!("{i}.bin"));
+        fs::write(&bundled, &contents).unwrap();
+        // Clients without gzip get the plain copy, so the gzipped one is only worth its
+        // flash when it's smaller
+        let gzipped = if gzipped.len() < contents.len() {
+            let bundled_gzip = assets_dir.join(format!("{i}.gz"));
+            fs::write(&bundled_gzip, &gzipped).unwrap();
+            // Another ETag, a cached copy is only current in the encoding it was sent in
+            format!(
+                "Some(Gzipped {{ etag: {:?}, body: include_bytes!({:?}) }})",
+                format!("\"{:016x}-gzip\"", fnv1a(&contents)),
+                bundled_gzip.display().to_string(),
+            )
+        } else {
+            "None".into()
+        };
+
+        writeln!(
+            table,
+            "    Asset {{ path: {:?}, content_type: {:?}, etag: {:?}, cache_control: {:?}, body: include_bytes!({:?}), gzipped: {} }},",
+            route,
+            content_type(file),
+            etag,
+            cache_control(&route),
+            bundled.display().to_string(),
+            gzipped,
+        )
+        .unwrap();
+    }
+    table.push_str("];\n");
+
+    fs::write(out.join("assets.rs"), table).unwrap();
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Hidden files, scripts and notes stay out of the firmware
+fn is_served(file: &Path) -> bool {
+    let name = file.file_name().unwrap().to_string_lossy();
+    !name.starts_with('.') && !matches!(extension(file).as_str(), "sh" | "md")
+}
+
+/// `index.html` is served at its directory and other pages drop the `.html`,
+/// so `wifi.html` is `/wifi`. Everything else keeps its file name
+fn route(relative: &Path) -> String {
+    let path = relative.to_string_lossy().replace('\\', "/");
+    if path == "index.html" {
+        return "/".into();
+    }
+    if let Some(directory) = path.strip_suffix("/index.html") {
+        return format!("/{directory}/");
+    }
+    match path.strip_suffix(".html") {
+        Some(page) => format!("/{page}"),
+        None => format!("/{path}"),
+    }
+}
+
+/// Pages are always revalidated so a firmware update shows up straight away. Scripts, styles
+/// and images can be reused for a day without asking
+fn cache_control(route: &str) -> &'static str {
+    if route.ends_with('/') || !route.rsplit('/').next().unwrap_or("").contains('.') {
+        "no-cache"
+    } else {
+        "public, max-age=86400"
+    }
+}
+
+/// 64 bit FNV-1a, plenty to tell versions of a file apart without another dependency
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn extension(file: &Path) -> String {
+    file.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+fn content_type(file: &Path) -> &'static str {
+    match extension(file).as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
 }
@@ -0,0 +1,103 @@
+//! Runs arbitrary bytes through a whole connection, split into reads at arbitrary
+//! points, so requests and bodies arrive in every possible piece
+#![no_main]
+
+use std::convert::Infallible;
+
+use embassy_futures::block_on;
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::String;
+use http_server::websocket::{Message, WebSocket};
+use http_server::{
+    ConnectionBuffers, HttpServer, Response, StatusCode, WebRequest, WebRequestHandler,
+    WebRequestHandlerError,
+};
+use libfuzzer_sys::fuzz_target;
+use serde::Deserialize;
+
+/// Hands the input out `read_size` bytes at a time, then EOF
+struct FuzzStream<'d> {
+    input: &'d [u8],
+    read_size: usize,
+}
+
+impl ErrorType for FuzzStream<'_> {
+    type Error = Infallible;
+}
+
+impl Read for FuzzStream<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        let n = self.read_size.min(buf.len()).min(self.input.len());
+        buf[..n].copy_from_slice(&self.input[..n]);
+        self.input = &self.input[n..];
+        Ok(n)
+    }
+}
+
+impl Write for FuzzStream<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        Ok(buf.len())
+    }
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct CommandRequest {
+    command: u8,
+}
+
+/// Reads the request every way the firmware's handler does
+struct FuzzHandler;
+
+impl WebRequestHandler for FuzzHandler {
+    async fn handle_request<'a>(
+        &'a self,
+        request: WebRequest<'_, '_>,
+        _response_buffer: &'a mut [u8],
+    ) -> Result<Response<'a>, WebRequestHandlerError> {
+        let path = request.path.ok_or(WebRequestHandlerError::NotFound)?;
+        let _ = request
+            .query_param("repeat")
+            .and_then(|repeat| repeat.parse::<u8>());
+        if request.is_form() {
+            for (_, value) in request.form_params()? {
+                let _ = value.to_string::<32>();
+            }
+        } else if !request.body.is_empty() {
+            request.json::<CommandRequest>()?;
+        }
+        match path {
+            "/" => Ok(Response::new_html(StatusCode::Ok, "ok")),
+            _ => Err(WebRequestHandlerError::NotFound),
+        }
+    }
+
+    fn accepts_websocket(&self, _request: &WebRequest<'_, '_>) -> bool {
+        true
+    }
+
+    async fn handle_websocket<S: Read + Write>(&self, websocket: &mut WebSocket<'_, S>) {
+        loop {
+            let reply: String<64> = match websocket.read().await {
+                Ok(Message::Text(text)) => String::try_from(text).unwrap_or_default(),
+                Ok(Message::Binary(_)) => String::new(),
+                Err(_) => return,
+            };
+            if websocket.send_text(&reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&read_size, input)) = data.split_first() else {
+        return;
+    };
+    let mut stream = FuzzStream {
+        input,
+        read_size: usize::from(read_size).max(1),
+    };
+    let mut buffers = Box::new(ConnectionBuffers::new());
+    block_on(HttpServer::new().serve_connection(&mut stream, None, &FuzzHandler, &mut buffers));
+});
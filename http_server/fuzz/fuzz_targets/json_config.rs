@@ -0,0 +1,35 @@
+//! Parses arbitrary request bodies as the Wi-Fi config the firmware accepts at
+//! `/SaveWifi` and `/api/v1/config`
+#![no_main]
+
+use heapless::String;
+use http_server::{HttpServer, ParsedRequest, WebRequestHandlerError};
+use libfuzzer_sys::fuzz_target;
+use serde::Deserialize;
+
+/// Same shape as `api::ConfigRequest` in the firmware
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct ConfigRequest {
+    wifi_ssid: String<32>,
+    wifi_password: String<32>,
+}
+
+fuzz_target!(|body: &[u8]| {
+    let mut request = format!(
+        "POST /api/v1/config HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+
+    let server = HttpServer::new();
+    let mut headers = [httparse::EMPTY_HEADER; 20];
+    if let ParsedRequest::Complete(request, _) = server.request_parser(&request, &mut headers) {
+        if let Err(err) = request.json::<ConfigRequest>() {
+            // Every failure has to be something the client can be told about
+            let err = WebRequestHandlerError::from(err);
+            assert!(!err.message().is_empty());
+        }
+    }
+});
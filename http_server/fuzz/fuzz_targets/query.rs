@@ -0,0 +1,25 @@
+//! Decodes arbitrary query strings and form bodies every way handlers do
+#![no_main]
+
+use http_server::url_encoding::UrlEncodedParams;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let params = UrlEncodedParams::new(data);
+    for (key, value) in params.clone() {
+        // Decoding is lazy, so the length can't be more than the raw text
+        assert!(key.decoded_bytes().count() <= key.raw().len());
+        assert!(value.decoded_bytes().count() <= value.raw().len());
+
+        let mut buffer = [0u8; 8];
+        if let Some(decoded) = value.decode_into(&mut buffer) {
+            assert!(value.eq_str(decoded));
+        }
+        let _ = key.to_string::<32>();
+        let _ = value.parse::<u8>();
+        let _ = value.parse::<i64>();
+        let _ = value.parse::<bool>();
+    }
+    let _ = params.get("repeat");
+    let _ = params.get("access_token");
+});
@@ -0,0 +1,35 @@
+//! Parses arbitrary bytes as a request and, when that works, reads every part of it
+//! the way a handler would
+#![no_main]
+
+use http_server::{HttpServer, ParsedRequest};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let server = HttpServer::new();
+    let mut headers = [httparse::EMPTY_HEADER; 20];
+    let ParsedRequest::Complete(request, request_len) = server.request_parser(data, &mut headers)
+    else {
+        return;
+    };
+    // The body has to come from inside the request, never past what was read
+    assert!(request_len <= data.len());
+    assert!(request.body.len() <= request_len);
+    assert!(request.path.is_some());
+
+    for (key, value) in request.query_params() {
+        let _ = key.to_string::<16>();
+        let _ = value.parse::<u8>();
+    }
+    if let Ok(form) = request.form_params() {
+        for (key, value) in form {
+            let _ = key.eq_str("wifi_ssid");
+            let _ = value.to_string::<32>();
+        }
+    }
+    let _ = request.header("Content-Type");
+    let _ = request.keep_alive();
+    let _ = request.accepts_json();
+    let _ = request.is_same_origin();
+    let _ = request.is_websocket_upgrade();
+});
@@ -0,0 +1,204 @@
+//! The most recent requests and running counters, kept in RAM so there is a record of
+//! who sent what when something goes wrong. Query strings are never logged since they
+//! can carry access tokens
+use core::cell::RefCell;
+use core::net::SocketAddr;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, String};
+use serde::Serialize;
+
+use crate::io::{format_truncated, try_format, BufWriter, Overflow};
+use crate::Method;
+
+/// How many requests are kept, the oldest is dropped to make room
+pub const ACCESS_LOG_LEN: usize = 16;
+/// Longer paths are cut off
+const PATH_LEN: usize = 48;
+
+#[derive(Clone)]
+pub struct AccessLogEntry {
+    /// Counts up from 1 for every request since boot, gaps mean entries were dropped
+    pub id: u32,
+    /// Uptime when the request was read
+    pub at: Instant,
+    pub remote: Option<SocketAddr>,
+    /// None for unknown methods and requests that couldn't be parsed
+    pub method: Option<Method>,
+    /// Without the query string
+    pub path: String<PATH_LEN>,
+    pub status: u16,
+    /// From the request being read to the response being written
+    pub latency: Duration,
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Counters {
+    pub requests: u32,
+    /// Responses with a 4xx status
+    pub client_errors: u32,
+    /// Responses with a 5xx status
+    pub server_errors: u32,
+    /// Requests that were malformed or too large to parse, also counted as client errors
+    pub parse_failures: u32,
+}
+
+struct Inner {
+    entries: Deque<AccessLogEntry, ACCESS_LOG_LEN>,
+    counters: Counters,
+    next_id: u32,
+}
+
+/// Shared by the server, which records to it, and handlers that show it. Usually a `static`
+pub struct AccessLog {
+    inner: Mutex<CriticalSectionRawMutex, RefCell<Inner>>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessLog {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                entries: Deque::new(),
+                counters: Counters {
+                    requests: 0,
+                    client_errors: 0,
+                    server_errors: 0,
+                    parse_failures: 0,
+                },
+                next_id: 1,
+            })),
+        }
+    }
+
+    /// Adds a handled request, `started` is when it was read
+    pub fn record(
+        &self,
+        remote: Option<SocketAddr>,
+        method: Option<Method>,
+        path: &str,
+        status: u16,
+        started: Instant,
+    ) {
+        self.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            let counters = &mut inner.counters;
+            counters.requests = counters.requests.wrapping_add(1);
+            match status {
+                400..=499 => counters.client_errors = counters.client_errors.wrapping_add(1),
+                500..=599 => counters.server_errors = counters.server_errors.wrapping_add(1),
+                _ => {}
+            }
+
+            let entry = AccessLogEntry {
+                id: inner.next_id,
+                at: started,
+                remote,
+                method,
+                path: format_truncated(format_args!("{}", path)),
+                status,
+                latency: started.elapsed(),
+            };
+            inner.next_id = inner.next_id.wrapping_add(1);
+            if inner.entries.is_full() {
+                inner.entries.pop_front();
+            }
+            let _ = inner.entries.push_back(entry);
+        });
+    }
+
+    /// Adds a request that was refused before it could be parsed
+    pub fn record_parse_failure(&self, remote: Option<SocketAddr>, status: u16) {
+        self.inner.lock(|inner| {
+            let counters = &mut inner.borrow_mut().counters;
+            counters.parse_failures = counters.parse_failures.wrapping_add(1);
+        });
+        self.record(remote, None, "", status, Instant::now());
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.inner.lock(|inner| inner.borrow().counters)
+    }
+
+    /// Calls `f` with every entry, newest first
+    pub fn for_each_newest_first(&self, mut f: impl FnMut(&AccessLogEntry)) {
+        self.inner
+            .lock(|inner| inner.borrow().entries.iter().rev().for_each(&mut f));
+    }
+
+    /// The counters and up to `limit` entries, newest first, as JSON. Entries that don't fit
+    /// in the buffer are left out so the newest are always there
+    pub fn write_json<'b>(&self, limit: usize, buffer: &'b mut [u8]) -> Result<&'b [u8], Overflow> {
+        const END: &[u8] = b"]}";
+        let Some(entries_end) = buffer.len().checked_sub(END.len()) else {
+            return Err(Overflow {
+                required: END.len(),
+            });
+        };
+
+        let mut len = write_str(buffer, 0, entries_end, "{\"counters\":")?;
+        len += serde_json_core::to_slice(&self.counters(), &mut buffer[len..entries_end])
+            .map_err(|_| Overflow { required: 128 })?;
+        len = write_str(buffer, len, entries_end, ",\"entries\":[")?;
+
+        let mut written = 0;
+        let mut full = false;
+        self.for_each_newest_first(|entry| {
+            let start = if written == 0 { len } else { len + 1 };
+            // Stop at the first entry that doesn't fit, the ones after it are older
+            if full || written >= limit || start >= entries_end {
+                return;
+            }
+            let remote = entry
+                .remote
+                .and_then(|remote| try_format::<48>(format_args!("{}", remote)).ok());
+            let json = EntryJson {
+                id: entry.id,
+                at_ms: entry.at.as_millis(),
+                remote: remote.as_deref(),
+                method: entry.method.map(|method| method.as_str()),
+                path: &entry.path,
+                status: entry.status,
+                latency_ms: entry.latency.as_millis(),
+            };
+            match serde_json_core::to_slice(&json, &mut buffer[start..entries_end]) {
+                Ok(entry_len) => {
+                    if written > 0 {
+                        buffer[len] = b',';
+                    }
+                    len = start + entry_len;
+                    written += 1;
+                }
+                Err(_) => full = true,
+            }
+        });
+
+        buffer[len..len + END.len()].copy_from_slice(END);
+        Ok(&buffer[..len + END.len()])
+    }
+}
+
+/// The JSON for one entry, times in milliseconds
+#[derive(Serialize)]
+struct EntryJson<'e> {
+    id: u32,
+    at_ms: u64,
+    remote: Option<&'e str>,
+    method: Option<&'static str>,
+    path: &'e str,
+    status: u16,
+    latency_ms: u64,
+}
+
+fn write_str(buffer: &mut [u8], at: usize, end: usize, text: &str) -> Result<usize, Overflow> {
+    let mut writer = BufWriter::new(&mut buffer[at..end]);
+    core::fmt::Write::write_str(&mut writer, text).map_err(|_| Overflow {
+        required: at + text.len(),
+    })?;
+    Ok(at + writer.len())
+}
@@ -0,0 +1,95 @@
+//! Clients that keep sending requests that can't be parsed, or keep getting the password
+//! wrong, are refused for a while. They're either broken, probing for holes or guessing, and
+//! either way are taking connection slots
+use core::cell::RefCell;
+use core::net::IpAddr;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// How many clients are remembered, the one with the oldest strike makes room for a new one
+const BAN_LIST_LEN: usize = 8;
+/// Strikes within `STRIKE_WINDOW` of each other that get a client banned
+const STRIKES_TO_BAN: u8 = 3;
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+const BAN_DURATION: Duration = Duration::from_secs(5 * 60);
+
+struct Client {
+    ip: IpAddr,
+    strikes: u8,
+    last_strike: Instant,
+    banned_until: Option<Instant>,
+}
+
+pub(crate) struct BanList {
+    clients: Mutex<CriticalSectionRawMutex, RefCell<Vec<Client, BAN_LIST_LEN>>>,
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BanList {
+    pub const fn new() -> Self {
+        Self {
+            clients: Mutex::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.clients.lock(|clients| {
+            clients.borrow().iter().any(|client| {
+                client.ip == ip && client.banned_until.is_some_and(|until| until > now)
+            })
+        })
+    }
+
+    /// Counts an unparseable request or failed login against the client, true when that got
+    /// it banned
+    pub fn strike(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.clients.lock(|clients| {
+            let mut clients = clients.borrow_mut();
+            let index = match clients.iter().position(|client| client.ip == ip) {
+                Some(index) => index,
+                None => {
+                    if clients.is_full() {
+                        // Prefer forgetting someone who isn't banned
+                        let oldest = (0..clients.len())
+                            .min_by_key(|&i| {
+                                (clients[i].banned_until.is_some(), clients[i].last_strike)
+                            })
+                            .unwrap_or(0);
+                        clients.swap_remove(oldest);
+                    }
+                    let _ = clients.push(Client {
+                        ip,
+                        strikes: 0,
+                        last_strike: now,
+                        banned_until: None,
+                    });
+                    clients.len() - 1
+                }
+            };
+
+            let client = &mut clients[index];
+            if client.banned_until.is_some_and(|until| until <= now) {
+                client.banned_until = None;
+            }
+            if now.saturating_duration_since(client.last_strike) > STRIKE_WINDOW {
+                client.strikes = 0;
+            }
+            client.strikes += 1;
+            client.last_strike = now;
+            if client.strikes >= STRIKES_TO_BAN {
+                client.strikes = 0;
+                client.banned_until = Some(now + BAN_DURATION);
+                return true;
+            }
+            false
+        })
+    }
+}
@@ -0,0 +1,73 @@
+//! Cross-Origin Resource Sharing, so pages served from somewhere else on the network can
+//! call the API. The server answers `OPTIONS` preflights itself and adds the
+//! `Access-Control-*` headers to every response for an allowed origin
+use crate::{Response, StatusCode};
+
+pub struct Cors {
+    /// Origins like `http://192.168.1.20:8080` that may call the server, `*` allows any
+    pub allowed_origins: &'static [&'static str],
+    /// Sent as `Access-Control-Allow-Methods` in preflight replies
+    pub allowed_methods: &'static str,
+    /// Sent as `Access-Control-Allow-Headers` in preflight replies
+    pub allowed_headers: &'static str,
+    /// Lets the browser send cookies and Basic auth it has cached. Can't be used with `*`
+    pub allow_credentials: bool,
+    /// How long the browser can cache a preflight reply
+    pub max_age_secs: u32,
+}
+
+impl Cors {
+    /// The value for `Access-Control-Allow-Origin`, None if the origin isn't allowed
+    pub fn allow_origin(&self, origin: &str) -> Option<&'static str> {
+        self.allowed_origins
+            .iter()
+            .find(|allowed| allowed.eq_ignore_ascii_case(origin))
+            .copied()
+            .or_else(|| {
+                // Credentials are never sent to a wildcard, the browser would refuse them anyway
+                (!self.allow_credentials && self.allowed_origins.contains(&"*")).then_some("*")
+            })
+    }
+
+    /// Adds the headers an allowed origin needs to read the response
+    pub fn add_headers(&self, response: &mut Response<'_>, origin: Option<&str>) {
+        let Some(allowed) = origin.and_then(|origin| self.allow_origin(origin)) else {
+            return;
+        };
+        response.add_header("Access-Control-Allow-Origin", allowed);
+        if allowed != "*" {
+            // Caches have to keep replies for different origins apart
+            response.add_header("Vary", "Origin");
+        }
+        if self.allow_credentials {
+            response.add_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    /// The `204` reply to a preflight `OPTIONS` request. Origins that aren't allowed get
+    /// no CORS headers, so the browser blocks the real request
+    pub fn preflight_response<'a>(&self, origin: Option<&str>) -> Response<'a> {
+        let mut response = Response::new_empty(StatusCode::NoContent);
+        if origin
+            .and_then(|origin| self.allow_origin(origin))
+            .is_none()
+        {
+            return response;
+        }
+        self.add_headers(&mut response, origin);
+        response.add_header("Access-Control-Allow-Methods", self.allowed_methods);
+        response.add_header("Access-Control-Allow-Headers", self.allowed_headers);
+        response.add_formatted_header(
+            "Access-Control-Max-Age",
+            format_args!("{}", self.max_age_secs),
+        );
+        response
+    }
+}
+
+/// The reply to a plain `OPTIONS` request that isn't a preflight
+pub fn options_response<'a>() -> Response<'a> {
+    let mut response = Response::new_empty(StatusCode::NoContent);
+    response.add_header("Allow", "GET, HEAD, POST, OPTIONS");
+    response
+}
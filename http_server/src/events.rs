@@ -0,0 +1,51 @@
+//! Events streamed to clients over Server-Sent Events. They are serialized when published,
+//! so the server doesn't need to know the application's event types
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use heapless::String;
+use serde::Serialize;
+
+use crate::HTTP_SOCKETS;
+
+/// How many events a slow subscriber can fall behind before it starts missing them
+const EVENT_CAPACITY: usize = 8;
+/// Longest JSON an event can serialize to
+pub const EVENT_DATA_LEN: usize = 192;
+
+/// One subscriber per connection is the most that can ever be listening
+pub type EventSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, ServerEvent, EVENT_CAPACITY, HTTP_SOCKETS, 0>;
+
+pub static EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    ServerEvent,
+    EVENT_CAPACITY,
+    HTTP_SOCKETS,
+    0,
+> = PubSubChannel::new();
+
+#[derive(Clone)]
+pub struct ServerEvent {
+    /// The SSE `event:` name
+    pub name: &'static str,
+    /// The event as JSON, sent as the `data:` line
+    pub data: String<EVENT_DATA_LEN>,
+}
+
+impl ServerEvent {
+    /// Serializes the value, None if its JSON is longer than `EVENT_DATA_LEN`
+    pub fn new<T: Serialize>(name: &'static str, value: &T) -> Option<Self> {
+        let mut buffer = [0u8; EVENT_DATA_LEN];
+        let len = serde_json_core::to_slice(value, &mut buffer).ok()?;
+        let data = core::str::from_utf8(&buffer[..len]).ok()?;
+        Some(Self {
+            name,
+            data: String::try_from(data).ok()?,
+        })
+    }
+}
+
+/// Publishes to every subscriber without waiting, the oldest event is dropped for anyone too far behind
+pub fn publish(event: ServerEvent) {
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
@@ -0,0 +1,130 @@
+use core::fmt::{Arguments, Write};
+use heapless::String;
+
+pub mod template;
+
+/// Formatted text didn't fit, `required` is how many bytes it needed
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Overflow {
+    pub required: usize,
+}
+
+/// Makes it easier to format strings in a single line method. Fails instead of
+/// panicking when the text is longer than `N`
+pub fn try_format<const N: usize>(args: Arguments<'_>) -> Result<String<N>, Overflow> {
+    let mut formatted_string: String<N> = String::<N>::new();
+
+    match core::fmt::write(&mut formatted_string, args) {
+        Ok(_) => Ok(formatted_string),
+        Err(_) => Err(Overflow {
+            required: formatted_len(args),
+        }),
+    }
+}
+
+/// Like `try_format` but cuts the text off at `N` bytes, for logs and labels where
+/// some text is better than none
+#[allow(dead_code)]
+pub fn format_truncated<const N: usize>(args: Arguments<'_>) -> String<N> {
+    let mut buffer = [0u8; N];
+    let formatted = format_str_truncated(args, &mut buffer);
+    // Can't fail, the text is at most N bytes
+    String::try_from(formatted).unwrap_or_default()
+}
+
+/// Formats into the buffer
+#[allow(dead_code)]
+pub fn try_format_str<'a>(args: Arguments<'_>, buffer: &'a mut [u8]) -> Result<&'a str, Overflow> {
+    let mut writer = BufWriter::new(buffer);
+    if core::fmt::write(&mut writer, args).is_err() {
+        return Err(Overflow {
+            required: formatted_len(args),
+        });
+    }
+    let len = writer.len();
+    Ok(writer_str(buffer, len))
+}
+
+/// Formats as much as fits into the buffer
+pub fn format_str_truncated<'a>(args: Arguments<'_>, buffer: &'a mut [u8]) -> &'a str {
+    let mut writer = BufWriter::new(buffer);
+    let _ = core::fmt::write(&mut writer, args);
+    let len = writer.len();
+    writer_str(buffer, len)
+}
+
+/// How many bytes the formatted text takes, without writing it anywhere
+pub fn formatted_len(args: Arguments<'_>) -> usize {
+    let mut counter = LengthCounter::default();
+    let _ = core::fmt::write(&mut counter, args);
+    counter.len()
+}
+
+/// `BufWriter` only ever stops at a char boundary, so this is always valid UTF-8
+fn writer_str(buffer: &[u8], len: usize) -> &str {
+    core::str::from_utf8(&buffer[..len]).unwrap_or_default()
+}
+
+// A simple wrapper struct to use core::fmt::Write on a [u8] buffer
+pub struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> BufWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        BufWriter { buf, pos: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+}
+
+impl<'a> core::fmt::Write for BufWriter<'a> {
+    /// Writes as much as fits, stopping at a char boundary, and errors if that wasn't all of it
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let space = self.buf.len() - self.pos;
+        if s.len() <= space {
+            self.buf[self.pos..self.pos + s.len()].copy_from_slice(s.as_bytes());
+            self.pos += s.len();
+            return Ok(());
+        }
+
+        let mut fits = space;
+        while !s.is_char_boundary(fits) {
+            fits -= 1;
+        }
+        self.buf[self.pos..self.pos + fits].copy_from_slice(&s.as_bytes()[..fits]);
+        self.pos += fits;
+        Err(core::fmt::Error) // Buffer overflow
+    }
+}
+
+/// Counts how long formatted text is, used to report how big a buffer would have needed to be
+#[derive(Default)]
+pub struct LengthCounter {
+    len: usize,
+}
+
+impl LengthCounter {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Write for LengthCounter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.len += s.len();
+        Ok(())
+    }
+}
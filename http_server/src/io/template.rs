@@ -0,0 +1,375 @@
+//! A tiny template engine for HTML pages.
+//!
+//! - `{{name}}` inserts a value with HTML escaping, `{{{name}}}` or `{{& name}}` inserts it as is
+//! - `{{#if name}}..{{else}}..{{/if}}` renders one branch depending on the value
+//! - `{{#each name}}..{{/each}}` repeats for every item of a list. Inside the loop names are
+//!   looked up on the item first, then outside it, and `{{.}}` is the item itself
+//!
+//! Unknown names and unbalanced sections are errors instead of rendering nothing,
+//! so typos show up the first time a page is loaded
+use core::fmt::{self, Write};
+
+use super::BufWriter;
+use crate::ChunkedBody;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Value<'a> {
+    Str(&'a str),
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    List(&'a dyn TemplateList),
+}
+
+impl Value<'_> {
+    /// False, zero, empty strings and empty lists skip an `{{#if}}`
+    fn is_truthy(&self) -> bool {
+        match self {
+            Self::Str(text) => !text.is_empty(),
+            Self::Bool(value) => *value,
+            Self::Int(value) => *value != 0,
+            Self::UInt(value) => *value != 0,
+            Self::List(list) => !list.is_empty(),
+        }
+    }
+}
+
+/// Anything a template can look names up in. Implemented for slices and arrays of
+/// `(name, Value)` pairs, which covers most pages without writing an impl
+pub trait TemplateContext {
+    fn value(&self, name: &str) -> Option<Value<'_>>;
+}
+
+/// A fixed collection `{{#each}}` can loop over
+pub trait TemplateList {
+    fn len(&self) -> usize;
+    fn item(&self, index: usize) -> Option<&dyn TemplateContext>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'v> TemplateContext for [(&str, Value<'v>)] {
+    fn value(&self, name: &str) -> Option<Value<'_>> {
+        self.iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+impl<'v, const N: usize> TemplateContext for [(&str, Value<'v>); N] {
+    fn value(&self, name: &str) -> Option<Value<'_>> {
+        self.as_slice().value(name)
+    }
+}
+
+/// Lets lists of plain strings be looped over with `{{.}}`
+impl TemplateContext for &str {
+    fn value(&self, name: &str) -> Option<Value<'_>> {
+        (name == ".").then_some(Value::Str(self))
+    }
+}
+
+impl<T: TemplateContext> TemplateList for [T] {
+    fn len(&self) -> usize {
+        <[T]>::len(self)
+    }
+
+    fn item(&self, index: usize) -> Option<&dyn TemplateContext> {
+        self.get(index).map(|item| item as &dyn TemplateContext)
+    }
+}
+
+impl<T: TemplateContext, const N: usize> TemplateList for [T; N] {
+    fn len(&self) -> usize {
+        N
+    }
+
+    fn item(&self, index: usize) -> Option<&dyn TemplateContext> {
+        self.get(index).map(|item| item as &dyn TemplateContext)
+    }
+}
+
+/// Renders the template into any writer, like a `BufWriter` or a `heapless::String`
+pub fn render<W: Write>(
+    template: &str,
+    context: &dyn TemplateContext,
+    out: &mut W,
+) -> Result<(), &'static str> {
+    render_section(
+        template,
+        &Scope {
+            context,
+            parent: None,
+        },
+        out,
+    )
+}
+
+/// Renders into the buffer, for pages that fit in memory
+pub fn render_to_str<'b>(
+    template: &str,
+    context: &dyn TemplateContext,
+    buffer: &'b mut [u8],
+) -> Result<&'b str, &'static str> {
+    let mut writer = BufWriter::new(buffer);
+    render(template, context, &mut writer)?;
+    let len = writer.len();
+    // Only whole strs were written so this can't fail
+    core::str::from_utf8(&buffer[..len]).map_err(|_| "Template output is not UTF-8")
+}
+
+/// Streams a template as a chunked response, for pages bigger than any buffer. The template
+/// is rendered again for every chunk and only the next piece is kept, trading time for memory
+#[allow(dead_code)]
+pub struct TemplateBody<'t> {
+    template: &'t str,
+    context: &'t dyn TemplateContext,
+    sent: usize,
+}
+
+#[allow(dead_code)]
+impl<'t> TemplateBody<'t> {
+    pub fn new(template: &'t str, context: &'t dyn TemplateContext) -> Self {
+        Self {
+            template,
+            context,
+            sent: 0,
+        }
+    }
+}
+
+impl ChunkedBody for TemplateBody<'_> {
+    fn next_chunk(&mut self, buffer: &mut [u8]) -> usize {
+        let mut window = WindowWriter {
+            buffer,
+            skip: self.sent,
+            len: 0,
+        };
+        // Filling the buffer stops the render with an error, anything else is the template's fault.
+        // The body then ends where the error is
+        if let Err(err) = render(self.template, self.context, &mut window) {
+            if window.len < window.buffer.len() {
+                warn!("Error rendering template: {}", err);
+            }
+        }
+        self.sent += window.len;
+        window.len
+    }
+}
+
+/// Throws away the first `skip` bytes written and keeps what comes after until the buffer is full
+struct WindowWriter<'b> {
+    buffer: &'b mut [u8],
+    skip: usize,
+    len: usize,
+}
+
+impl Write for WindowWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        let skipped = self.skip.min(bytes.len());
+        self.skip -= skipped;
+        bytes = &bytes[skipped..];
+
+        let space = self.buffer.len() - self.len;
+        let take = bytes.len().min(space);
+        self.buffer[self.len..self.len + take].copy_from_slice(&bytes[..take]);
+        self.len += take;
+        if take < bytes.len() {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+/// Names are looked up in the innermost `{{#each}}` item first
+struct Scope<'s> {
+    context: &'s dyn TemplateContext,
+    parent: Option<&'s Scope<'s>>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, name: &str) -> Result<Value<'_>, &'static str> {
+        match self.context.value(name) {
+            Some(value) => Ok(value),
+            None => match self.parent {
+                Some(parent) => parent.lookup(name),
+                None => {
+                    warn!("Unknown template value {}", name);
+                    Err("Unknown template value")
+                }
+            },
+        }
+    }
+}
+
+enum TagKind<'t> {
+    Value(&'t str),
+    Raw(&'t str),
+    If(&'t str),
+    Each(&'t str),
+    Else,
+    End(&'t str),
+}
+
+struct Tag<'t> {
+    kind: TagKind<'t>,
+    /// Where the `{{` is
+    start: usize,
+    /// Just past the `}}`
+    end: usize,
+}
+
+const OUTPUT_TOO_LONG: &str = "Template output doesn't fit";
+
+fn render_section<W: Write>(
+    template: &str,
+    scope: &Scope<'_>,
+    out: &mut W,
+) -> Result<(), &'static str> {
+    let mut position = 0;
+    while let Some(tag) = next_tag(template, position)? {
+        out.write_str(&template[position..tag.start])
+            .map_err(|_| OUTPUT_TOO_LONG)?;
+        position = tag.end;
+
+        match tag.kind {
+            TagKind::Value(name) => write_value(out, scope.lookup(name)?, true)?,
+            TagKind::Raw(name) => write_value(out, scope.lookup(name)?, false)?,
+            TagKind::If(name) => {
+                let (else_tag, end) = find_section_end(template, tag.end, "if")?;
+                let branch = match (scope.lookup(name)?.is_truthy(), else_tag) {
+                    (true, Some(else_tag)) => &template[tag.end..else_tag.start],
+                    (true, None) => &template[tag.end..end.start],
+                    (false, Some(else_tag)) => &template[else_tag.end..end.start],
+                    (false, None) => "",
+                };
+                render_section(branch, scope, out)?;
+                position = end.end;
+            }
+            TagKind::Each(name) => {
+                let (else_tag, end) = find_section_end(template, tag.end, "each")?;
+                if else_tag.is_some() {
+                    return Err("{{else}} only works in {{#if}}");
+                }
+                let Value::List(list) = scope.lookup(name)? else {
+                    return Err("{{#each}} needs a list");
+                };
+                let body = &template[tag.end..end.start];
+                for index in 0..list.len() {
+                    if let Some(item) = list.item(index) {
+                        let item_scope = Scope {
+                            context: item,
+                            parent: Some(scope),
+                        };
+                        render_section(body, &item_scope, out)?;
+                    }
+                }
+                position = end.end;
+            }
+            TagKind::Else | TagKind::End(_) => {
+                return Err("Template section ends without starting")
+            }
+        }
+    }
+    out.write_str(&template[position..])
+        .map_err(|_| OUTPUT_TOO_LONG)
+}
+
+fn write_value<W: Write>(out: &mut W, value: Value<'_>, escape: bool) -> Result<(), &'static str> {
+    let written = match value {
+        Value::Str(text) if escape => write_escaped(out, text),
+        Value::Str(text) => out.write_str(text),
+        Value::Bool(value) => out.write_str(if value { "true" } else { "false" }),
+        Value::Int(value) => write!(out, "{}", value),
+        Value::UInt(value) => write!(out, "{}", value),
+        Value::List(_) => return Err("Lists can only be used with {{#each}}"),
+    };
+    written.map_err(|_| OUTPUT_TOO_LONG)
+}
+
+fn write_escaped<W: Write>(out: &mut W, text: &str) -> fmt::Result {
+    let mut last = 0;
+    for (i, c) in text.char_indices() {
+        let entity = match c {
+            '&' => "&amp;",
+            '<' => "&lt;",
+            '>' => "&gt;",
+            '"' => "&quot;",
+            '\'' => "&#39;",
+            _ => continue,
+        };
+        out.write_str(&text[last..i])?;
+        out.write_str(entity)?;
+        last = i + 1;
+    }
+    out.write_str(&text[last..])
+}
+
+fn next_tag(template: &str, from: usize) -> Result<Option<Tag<'_>>, &'static str> {
+    let Some(offset) = template[from..].find("{{") else {
+        return Ok(None);
+    };
+    let start = from + offset;
+    let (inner_start, close) = if template[start..].starts_with("{{{") {
+        (start + 3, "}}}")
+    } else {
+        (start + 2, "}}")
+    };
+    let inner_len = template[inner_start..]
+        .find(close)
+        .ok_or("Template tag is never closed")?;
+    let inner = template[inner_start..inner_start + inner_len].trim();
+
+    let kind = if close == "}}}" {
+        TagKind::Raw(inner)
+    } else if let Some(name) = inner.strip_prefix('&') {
+        TagKind::Raw(name.trim())
+    } else if let Some(name) = inner.strip_prefix("#if ") {
+        TagKind::If(name.trim())
+    } else if let Some(name) = inner.strip_prefix("#each ") {
+        TagKind::Each(name.trim())
+    } else if inner == "else" {
+        TagKind::Else
+    } else if let Some(name) = inner.strip_prefix('/') {
+        TagKind::End(name.trim())
+    } else {
+        TagKind::Value(inner)
+    };
+    Ok(Some(Tag {
+        kind,
+        start,
+        end: inner_start + inner_len + close.len(),
+    }))
+}
+
+/// Finds the `{{/section}}` closing a section whose body starts at `from`, along with
+/// the `{{else}}` that belongs to it if there is one
+fn find_section_end<'t>(
+    template: &'t str,
+    from: usize,
+    section: &str,
+) -> Result<(Option<Tag<'t>>, Tag<'t>), &'static str> {
+    let mut depth = 0;
+    let mut else_tag = None;
+    let mut position = from;
+    while let Some(tag) = next_tag(template, position)? {
+        position = tag.end;
+        match tag.kind {
+            TagKind::If(_) | TagKind::Each(_) => depth += 1,
+            TagKind::End(name) if depth == 0 => {
+                if name != section {
+                    return Err("Template sections are closed in the wrong order");
+                }
+                return Ok((else_tag, tag));
+            }
+            TagKind::End(_) => depth -= 1,
+            TagKind::Else if depth == 0 => else_tag = Some(tag),
+            _ => {}
+        }
+    }
+    Err("Template section is never closed")
+}
@@ -0,0 +1,1587 @@
+#![no_std]
+#![allow(async_fn_in_trait)]
+
+use core::fmt::{write as fmt_write, Arguments};
+use core::net::SocketAddr;
+use core::str;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{with_deadline, with_timeout, Duration, Instant};
+use embedded_io_async::{Error as _, Read, Write};
+use heapless::{String, Vec};
+use httparse::Header;
+use serde::{Deserialize, Serialize};
+
+use access_log::AccessLog;
+use ban_list::BanList;
+use cors::Cors;
+use events::{EventSubscriber, ServerEvent, EVENTS};
+use io::{try_format, BufWriter, LengthCounter};
+use timeouts::{Timeouts, WriteTimeout};
+use url_encoding::{UrlEncoded, UrlEncodedParams};
+use websocket::WebSocket;
+
+// Has to come first so the logging macros are visible in the other modules
+#[macro_use]
+mod fmt;
+
+pub mod access_log;
+mod ban_list;
+pub mod cors;
+pub mod events;
+pub mod io;
+pub mod rate_limit;
+#[cfg(feature = "embassy-net")]
+mod tcp;
+pub mod timeouts;
+pub mod url_encoding;
+pub mod websocket;
+
+/// How many connections are served at the same time
+pub const HTTP_SOCKETS: usize = 4;
+/// Largest request, headers and body together, that can be handled
+pub const REQUEST_BUFFER_LEN: usize = 4_096;
+/// After this many requests the connection is closed so one client can't hold the socket forever
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+/// WebSocket handlers are expected to ping more often than this to keep the connection open
+const WEBSOCKET_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often an idle event stream gets a comment line so dead clients are noticed
+const EVENT_STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Default)]
+pub struct HttpServer {
+    cors: Option<Cors>,
+    auth_challenge: Option<&'static str>,
+    access_log: Option<&'static AccessLog>,
+    timeouts: Timeouts,
+    bans: BanList,
+}
+
+/// Everything a connection needs besides the socket. Every connection served at the
+/// same time needs its own
+pub struct ConnectionBuffers {
+    /// Bytes read from the client that haven't been handled yet
+    request: [u8; REQUEST_BUFFER_LEN],
+    /// Handed to the handler to build its response in
+    handler: [u8; 2_048],
+    /// Only holds the response head and small bodies, anything bigger is streamed
+    response: [u8; 1_024],
+    /// Error bodies are built here since a failed handler still holds the handler buffer
+    error: [u8; 256],
+}
+
+impl ConnectionBuffers {
+    pub const fn new() -> Self {
+        Self {
+            request: [0; REQUEST_BUFFER_LEN],
+            handler: [0; 2_048],
+            response: [0; 1_024],
+            error: [0; 256],
+        }
+    }
+}
+
+impl Default for ConnectionBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpServer {
+    pub const fn new() -> Self {
+        Self {
+            cors: None,
+            auth_challenge: None,
+            access_log: None,
+            timeouts: Timeouts::DEFAULT,
+            bans: BanList::new(),
+        }
+    }
+
+    /// Lets pages from other origins call the server. Without it no CORS headers are sent
+    pub fn with_cors(mut self, cors: Cors) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Sent as `WWW-Authenticate` with every `401`, like `Basic realm="Device"`, so
+    /// browsers know to ask for a login
+    pub fn with_auth_challenge(mut self, challenge: &'static str) -> Self {
+        self.auth_challenge = Some(challenge);
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Records every response to the log, which handlers can share to show it
+    pub fn with_access_log(mut self, access_log: &'static AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Answers requests on the connection until either side closes it, it sits idle or it is
+    /// upgraded to a WebSocket and the handler is done with it. Closing the connection
+    /// afterwards is left to the caller. `remote` goes to the access log and the handler, and
+    /// clients that keep sending garbage are refused by it for a while
+    pub async fn serve_connection<S, H>(
+        &self,
+        stream: &mut S,
+        remote: Option<SocketAddr>,
+        handler: &H,
+        buffers: &mut ConnectionBuffers,
+    ) where
+        S: Read + Write,
+        H: WebRequestHandler,
+    {
+        let ConnectionBuffers {
+            request: buf,
+            handler: request_response_buffer,
+            response: response_buffer,
+            error: error_buffer,
+        } = buffers;
+
+        if remote.is_some_and(|remote| self.bans.is_banned(remote.ip())) {
+            debug!("Refusing a banned client");
+            return;
+        }
+        let stream = &mut WriteTimeout::new(stream, self.timeouts.write);
+
+        // How much of buf holds bytes that have been read but not handled yet
+        let mut filled = 0;
+        let mut requests_handled = 0;
+        let mut keep_alive = true;
+        // When the request being read has to be in by. The first one's headers are timed from
+        // the connection opening so a client can't hold the socket without sending anything
+        let mut deadline = Instant::now() + self.timeouts.header;
+        let mut reading_body = false;
+
+        while keep_alive {
+            let n = match with_deadline(deadline, stream.read(&mut buf[filled..])).await {
+                Ok(Ok(0)) => {
+                    debug!("read EOF");
+                    break;
+                }
+                Ok(Ok(n)) => n,
+                Ok(Err(e)) => {
+                    warn!("read error: {:?}", e.kind());
+                    break;
+                }
+                Err(_) => {
+                    debug!("Client took too long, closing");
+                    break;
+                }
+            };
+            // Idling between keep-alive requests is over once the next one starts
+            if filled == 0 && requests_handled > 0 {
+                deadline = Instant::now() + self.timeouts.header;
+            }
+            filled += n;
+
+            // A single read can hold more than one request when the client is pipelining,
+            // so answer every complete request in the buffer in order before reading again
+            while keep_alive {
+                let mut headers = [httparse::EMPTY_HEADER; 20];
+                let (mut request, request_len) = match self
+                    .request_parser(&buf[..filled], &mut headers)
+                {
+                    ParsedRequest::Complete(request, request_len) => (request, request_len),
+                    // Headers can't outgrow the buffer either
+                    ParsedRequest::Partial if filled < buf.len() => break,
+                    ParsedRequest::PartialBody => {
+                        if !reading_body {
+                            reading_body = true;
+                            deadline = Instant::now() + self.timeouts.body;
+                        }
+                        break;
+                    }
+                    ParsedRequest::Partial | ParsedRequest::TooLarge => {
+                        warn!("Request does not fit in the request buffer");
+                        let response = self.error_response(
+                            WebRequestHandlerError::PayloadTooLarge,
+                            false,
+                            error_buffer,
+                        );
+                        self.log_parse_failure(remote, &response);
+                        Self::send_response(stream, response, false, true, false, response_buffer)
+                            .await;
+                        keep_alive = false;
+                        break;
+                    }
+                    ParsedRequest::Invalid => {
+                        warn!("Was not a proper web request");
+                        self.strike(remote);
+                        let response = self.error_response(
+                            WebRequestHandlerError::BadRequest("Was not a proper web request"),
+                            false,
+                            error_buffer,
+                        );
+                        self.log_parse_failure(remote, &response);
+                        Self::send_response(stream, response, false, true, false, response_buffer)
+                            .await;
+                        keep_alive = false;
+                        break;
+                    }
+                };
+
+                let started = Instant::now();
+                request.remote = remote;
+                // Taken before HEAD is rewritten, the path stays borrowed from buf until the
+                // response is sent
+                let (method, path) = (request.method, request.path.unwrap_or(""));
+
+                if request.is_websocket_upgrade()
+                    && self.check_origin(&request, handler).is_ok()
+                    && handler.accepts_websocket(&request)
+                {
+                    let accept =
+                        websocket::accept_key(request.header("Sec-WebSocket-Key").unwrap_or(""));
+                    info!("Upgrading connection to a WebSocket");
+                    if websocket::write_handshake(stream, &accept).await.is_ok() {
+                        self.log(remote, method, path, 101, started);
+                        // Frames sent straight after the handshake may already be in the buffer
+                        buf.copy_within(request_len..filled, 0);
+                        let mut websocket = WebSocket::new(stream, buf, filled - request_len);
+                        websocket.set_timeout(Some(WEBSOCKET_TIMEOUT));
+                        websocket.set_remote(remote);
+                        handler.handle_websocket(&mut websocket).await;
+                    }
+                    return;
+                }
+
+                requests_handled += 1;
+                keep_alive = request.keep_alive() && requests_handled < MAX_REQUESTS_PER_CONNECTION;
+                let http_1_1 = request.version >= 1;
+                let wants_json = request.accepts_json();
+                let origin = request.header("Origin");
+                // Handlers see HEAD as GET, the body is dropped when the response is sent
+                let head_only = request.method == Some(Method::Head);
+                if head_only {
+                    request.method = Some(Method::Get);
+                }
+
+                // OPTIONS is answered here so preflights never need credentials
+                let mut response = if request.method == Some(Method::Options) {
+                    match &self.cors {
+                        Some(cors) if request.header("Access-Control-Request-Method").is_some() => {
+                            cors.preflight_response(origin)
+                        }
+                        _ => cors::options_response(),
+                    }
+                } else if let Err(err) = self.check_origin(&request, handler) {
+                    warn!("Blocked cross-origin request from {:?}", origin);
+                    self.error_response(err, wants_json, error_buffer)
+                } else {
+                    match handler
+                        .handle_request(request, request_response_buffer)
+                        .await
+                    {
+                        Ok(response) => response,
+                        Err(err) => {
+                            warn!("Request handler error: {:?}", err);
+                            // Guessing passwords gets a client banned the same as garbage does
+                            if matches!(err, WebRequestHandlerError::BadCredentials)
+                                && self.strike(remote)
+                            {
+                                keep_alive = false;
+                            }
+                            self.error_response(err, wants_json, error_buffer)
+                        }
+                    }
+                };
+                if let Some(cors) = &self.cors {
+                    if !response.has_header("Access-Control-Allow-Origin") {
+                        cors.add_headers(&mut response, origin);
+                    }
+                }
+
+                let status = response.status_code.as_u16();
+                keep_alive = Self::send_response(
+                    stream,
+                    response,
+                    keep_alive,
+                    http_1_1,
+                    head_only,
+                    response_buffer,
+                )
+                .await;
+                self.log(remote, method, path, status, started);
+                if !keep_alive {
+                    break;
+                }
+
+                // Move any pipelined bytes to the front of the buffer
+                buf.copy_within(request_len..filled, 0);
+                filled -= request_len;
+                reading_body = false;
+                deadline = Instant::now()
+                    + if filled > 0 {
+                        self.timeouts.header
+                    } else {
+                        self.timeouts.keep_alive
+                    };
+            }
+        }
+    }
+
+    fn log(
+        &self,
+        remote: Option<SocketAddr>,
+        method: Option<Method>,
+        path: &str,
+        status: u16,
+        started: Instant,
+    ) {
+        if let Some(access_log) = self.access_log {
+            access_log.record(remote, method, path, status, started);
+        }
+    }
+
+    /// Counts a strike against the client, true when that got it banned
+    fn strike(&self, remote: Option<SocketAddr>) -> bool {
+        let Some(remote) = remote else {
+            return false;
+        };
+        let banned = self.bans.strike(remote.ip());
+        if banned {
+            let ip = try_format::<48>(format_args!("{}", remote.ip()));
+            warn!(
+                "Banning {} for repeated bad requests",
+                ip.as_deref().unwrap_or("")
+            );
+        }
+        banned
+    }
+
+    fn log_parse_failure(&self, remote: Option<SocketAddr>, response: &Response<'_>) {
+        if let Some(access_log) = self.access_log {
+            access_log.record_parse_failure(remote, response.status_code.as_u16());
+        }
+    }
+
+    /// The error as a response, asking the browser to log in when it is a `401`
+    fn error_response<'b>(
+        &self,
+        err: WebRequestHandlerError,
+        json: bool,
+        error_buffer: &'b mut [u8],
+    ) -> Response<'b> {
+        let unauthorized = matches!(
+            err,
+            WebRequestHandlerError::Unauthorized | WebRequestHandlerError::BadCredentials
+        );
+        let mut response = err.into_response(json, error_buffer);
+        if let (true, Some(challenge)) = (unauthorized, self.auth_challenge) {
+            response.add_header("WWW-Authenticate", challenge);
+        }
+        response
+    }
+
+    /// Stops other sites from using a visitor's browser, and the login it remembers, to change
+    /// things on the device. Only requests that can change state are checked
+    fn check_origin<H: WebRequestHandler>(
+        &self,
+        request: &WebRequest<'_, '_>,
+        handler: &H,
+    ) -> Result<(), WebRequestHandlerError> {
+        let changes_state = request.is_websocket_upgrade()
+            || !request.method.is_some_and(|method| method.is_safe());
+        if !changes_state || request.is_same_origin() != Some(false) {
+            return Ok(());
+        }
+
+        // Origins CORS lists by name are trusted. Anything else has to carry credentials the
+        // handler has checked and that the browser never adds on its own, only it knows which
+        // tokens are real
+        let listed = match (&self.cors, request.header("Origin")) {
+            (Some(cors), Some(origin)) => cors
+                .allow_origin(origin)
+                .is_some_and(|allowed| allowed != "*"),
+            _ => false,
+        };
+        if listed || handler.trusts_cross_origin(request) {
+            Ok(())
+        } else {
+            Err(WebRequestHandlerError::Forbidden(
+                "Cross-origin requests need a valid bearer token",
+            ))
+        }
+    }
+
+    /// Writes the response out with the matching `Connection` header, `head_only` leaves the body
+    /// out for HEAD requests. Returns if the connection can stay open for another request
+    async fn send_response<S: Write>(
+        stream: &mut S,
+        mut response: Response<'_>,
+        keep_alive: bool,
+        http_1_1: bool,
+        head_only: bool,
+        response_buffer: &mut [u8],
+    ) -> bool {
+        // HTTP/1.0 can't do chunked encoding so closing the connection is what ends the body
+        let use_chunked_encoding = http_1_1;
+        let keep_alive = keep_alive
+            && (use_chunked_encoding || !response.is_chunked())
+            && !response.is_event_stream();
+        response.add_header(
+            "Connection",
+            if keep_alive { "keep-alive" } else { "close" },
+        );
+
+        let buffer_len = response_buffer.len();
+        let mut writer: BufWriter<'_> = BufWriter::new(response_buffer);
+        if response
+            .write_head(&mut writer, use_chunked_encoding)
+            .is_err()
+        {
+            let mut counter = LengthCounter::default();
+            let _ = response.write_head(&mut counter, use_chunked_encoding);
+            warn!(
+                "Response head needs {} bytes but the buffer is {}, sending a 500",
+                counter.len(),
+                buffer_len
+            );
+            let mut bad_response_buffer = [0u8; 300];
+            let mut bad_response =
+                Response::new_html(StatusCode::InternalServerError, "Error writing response");
+            bad_response.add_header("Connection", "close");
+            let mut writer: BufWriter<'_> = BufWriter::new(&mut bad_response_buffer);
+            if bad_response.write_head(&mut writer, false).is_err() {
+                warn!("Error writing any response");
+                return false;
+            }
+            let bad_response_len = writer.len();
+            //Already a hail mary, so just ignore the error
+            let _ = stream
+                .write_all(&bad_response_buffer[..bad_response_len])
+                .await;
+            let _ = stream.write_all(b"Error writing response").await;
+            return false;
+        }
+
+        //trim the buffer to the actual size
+        let head_len: usize = writer.len();
+
+        if head_only {
+            return match stream.write_all(&response_buffer[..head_len]).await {
+                Ok(()) => keep_alive,
+                Err(e) => {
+                    warn!("write error: {:?}", e.kind());
+                    false
+                }
+            };
+        }
+
+        let result = match response.body {
+            Body::Empty => stream.write_all(&response_buffer[..head_len]).await,
+            Body::Bytes(body) => {
+                // Small bodies go out with the head in one write, bigger ones are streamed from where they are
+                if head_len + body.len() <= response_buffer.len() {
+                    response_buffer[head_len..head_len + body.len()].copy_from_slice(body);
+                    stream
+                        .write_all(&response_buffer[..head_len + body.len()])
+                        .await
+                } else {
+                    match stream.write_all(&response_buffer[..head_len]).await {
+                        Ok(()) => stream.write_all(body).await,
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+            Body::Chunked(body) => match stream.write_all(&response_buffer[..head_len]).await {
+                Ok(()) => {
+                    Self::write_chunked_body(stream, body, use_chunked_encoding, response_buffer)
+                        .await
+                }
+                Err(e) => Err(e),
+            },
+            Body::EventStream(mut subscriber, initial_event) => {
+                match stream.write_all(&response_buffer[..head_len]).await {
+                    Ok(()) => {
+                        Self::write_event_stream(
+                            stream,
+                            &mut subscriber,
+                            initial_event,
+                            response_buffer,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        // Anything the stream holds back has to go out before the next read waits on the client
+        let result = match result {
+            Ok(()) => stream.flush().await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => keep_alive,
+            Err(e) => {
+                warn!("write error: {:?}", e.kind());
+                false
+            }
+        }
+    }
+
+    /// Forwards events to the client until writing fails because it went away
+    async fn write_event_stream<S: Write>(
+        stream: &mut S,
+        subscriber: &mut EventSubscriber,
+        initial_event: Option<ServerEvent>,
+        buffer: &mut [u8],
+    ) -> Result<(), S::Error> {
+        match initial_event {
+            Some(event) => Self::write_event(stream, &event, buffer).await?,
+            None => stream.write_all(b": connected\n\n").await?,
+        }
+        stream.flush().await?;
+
+        loop {
+            match with_timeout(EVENT_STREAM_KEEP_ALIVE, subscriber.next_message()).await {
+                Ok(WaitResult::Message(event)) => Self::write_event(stream, &event, buffer).await?,
+                Ok(WaitResult::Lagged(missed)) => warn!("Event stream missed {} events", missed),
+                // Clients ignore comments, but a dead connection shows up as a failed write
+                Err(_) => stream.write_all(b": keep-alive\n\n").await?,
+            }
+            stream.flush().await?;
+        }
+    }
+
+    async fn write_event<S: Write>(
+        stream: &mut S,
+        event: &ServerEvent,
+        buffer: &mut [u8],
+    ) -> Result<(), S::Error> {
+        let mut writer = BufWriter::new(buffer);
+        // JSON from serde_json_core never has raw newlines so it is always a single data line
+        let written = fmt_write(
+            &mut writer,
+            format_args!("event: {}\ndata: {}\n\n", event.name, event.data),
+        );
+        let len = writer.len();
+        if written.is_err() {
+            warn!("Event {} did not fit in the buffer", event.name);
+            return Ok(());
+        }
+        stream.write_all(&buffer[..len]).await
+    }
+
+    /// Pulls chunks from the body and writes them to the stream until it is done
+    async fn write_chunked_body<S: Write>(
+        stream: &mut S,
+        body: &mut dyn ChunkedBody,
+        use_chunked_encoding: bool,
+        buffer: &mut [u8],
+    ) -> Result<(), S::Error> {
+        // Room before the data for the chunk size line and after it for the trailing \r\n,
+        // so each chunk goes out in a single write
+        const CHUNK_SIZE_LEN: usize = 10;
+        let data_end = buffer.len() - 2;
+
+        loop {
+            let n = body.next_chunk(&mut buffer[CHUNK_SIZE_LEN..data_end]);
+            if !use_chunked_encoding {
+                if n == 0 {
+                    return Ok(());
+                }
+                stream
+                    .write_all(&buffer[CHUNK_SIZE_LEN..CHUNK_SIZE_LEN + n])
+                    .await?;
+                continue;
+            }
+            if n == 0 {
+                return stream.write_all(b"0\r\n\r\n").await;
+            }
+
+            // A usize in hex plus \r\n always fits, but don't take the device down if it doesn't
+            let Ok(chunk_size) = try_format::<CHUNK_SIZE_LEN>(format_args!("{:X}\r\n", n)) else {
+                warn!("Chunk size did not fit");
+                return Ok(());
+            };
+            let start = CHUNK_SIZE_LEN - chunk_size.len();
+            buffer[start..CHUNK_SIZE_LEN].copy_from_slice(chunk_size.as_bytes());
+            buffer[CHUNK_SIZE_LEN + n..CHUNK_SIZE_LEN + n + 2].copy_from_slice(b"\r\n");
+            stream
+                .write_all(&buffer[start..CHUNK_SIZE_LEN + n + 2])
+                .await?;
+        }
+    }
+
+    pub fn request_parser<'headers, 'buf>(
+        &self,
+        request_buffer: &'buf [u8],
+        headers: &'headers mut [Header<'buf>],
+    ) -> ParsedRequest<'headers, 'buf> {
+        let mut request: httparse::Request<'headers, 'buf> = httparse::Request::new(headers);
+        let headers_len = match request.parse(request_buffer) {
+            Ok(httparse::Status::Complete(headers_len)) => headers_len,
+            Ok(httparse::Status::Partial) => return ParsedRequest::Partial,
+            Err(_) => {
+                info!("Failed to parse request");
+                return ParsedRequest::Invalid;
+            }
+        };
+
+        let mut content_length = 0;
+        for header in request.headers.iter() {
+            if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+                info!("Chunked request bodies are not supported");
+                return ParsedRequest::Invalid;
+            }
+            if header.name.eq_ignore_ascii_case("Content-Length") {
+                let parsed = str::from_utf8(header.value)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<usize>().ok());
+                match parsed {
+                    Some(length) => content_length = length,
+                    None => {
+                        info!("Invalid Content-Length");
+                        return ParsedRequest::Invalid;
+                    }
+                }
+            }
+        }
+
+        // No point waiting for a body that could never fit
+        let request_len = match headers_len.checked_add(content_length) {
+            Some(request_len) if request_len <= REQUEST_BUFFER_LEN => request_len,
+            _ => return ParsedRequest::TooLarge,
+        };
+        // Wait for the rest of the body before handing the request off
+        if request_buffer.len() < request_len {
+            return ParsedRequest::PartialBody;
+        }
+
+        let body = &request_buffer[headers_len..request_len];
+
+        // Handlers match on the path alone, the query string is parsed on demand
+        let (path, query) = match request.path {
+            Some(target) => match target.split_once('?') {
+                Some((path, query)) => (Some(path), Some(query)),
+                None => (Some(target), None),
+            },
+            None => (None, None),
+        };
+
+        ParsedRequest::Complete(
+            WebRequest {
+                method: request.method.and_then(Method::new),
+                remote: None,
+                path,
+                query,
+                version: request.version.unwrap_or(1),
+                body,
+                headers: request.headers,
+            },
+            request_len,
+        )
+    }
+}
+
+pub enum ParsedRequest<'headers, 'buf> {
+    /// A full request and how many bytes of the buffer it used
+    Complete(WebRequest<'headers, 'buf>, usize),
+    /// Need to read more from the socket before the headers are all in
+    Partial,
+    /// The headers are in but the body isn't yet
+    PartialBody,
+    /// The request says it is bigger than the request buffer
+    TooLarge,
+    Invalid,
+}
+
+#[allow(dead_code)]
+pub struct WebRequest<'headers, 'buf> {
+    pub method: Option<Method>,
+    /// Who sent it, when the connection knows
+    pub remote: Option<SocketAddr>,
+    /// Path without the query string
+    pub path: Option<&'buf str>,
+    /// Everything after the `?`, still percent-encoded
+    pub query: Option<&'buf str>,
+    /// Minor version of HTTP/1.x
+    pub version: u8,
+    pub body: &'buf [u8],
+    pub headers: &'headers mut [Header<'buf>],
+}
+
+#[allow(dead_code)]
+impl<'headers, 'buf> WebRequest<'headers, 'buf> {
+    /// Value of the first header matching the name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&'buf str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .and_then(|header| str::from_utf8(header.value).ok())
+    }
+
+    pub fn query_params(&self) -> UrlEncodedParams<'buf> {
+        UrlEncodedParams::new(self.query.unwrap_or(""))
+    }
+
+    pub fn query_param(&self, key: &str) -> Option<UrlEncoded<'buf>> {
+        self.query_params().get(key)
+    }
+
+    /// Fields of an `application/x-www-form-urlencoded` body, like a plain HTML form post
+    pub fn form_params(&self) -> Result<UrlEncodedParams<'buf>, WebRequestHandlerError> {
+        if !self.is_form() {
+            return Err(WebRequestHandlerError::BadRequest(
+                "Expected a form-urlencoded body",
+            ));
+        }
+        match str::from_utf8(self.body) {
+            Ok(body) => Ok(UrlEncodedParams::new(body)),
+            Err(_) => Err(WebRequestHandlerError::BadRequest(
+                "Form body is not valid UTF-8",
+            )),
+        }
+    }
+
+    pub fn is_form(&self) -> bool {
+        self.header("Content-Type").is_some_and(|content_type| {
+            content_type
+                .trim_start()
+                .starts_with("application/x-www-form-urlencoded")
+        })
+    }
+
+    /// Parses the body as JSON. The error converts into a `WebRequestHandlerError::BadRequest`
+    pub fn json<T: Deserialize<'buf>>(&self) -> Result<T, JsonBodyError> {
+        if self.body.is_empty() {
+            return Err(JsonBodyError::EmptyBody);
+        }
+        match serde_json_core::from_slice::<T>(self.body) {
+            Ok((value, _)) => Ok(value),
+            Err(e) => {
+                let message = json_error_message(e);
+                warn!("Error parsing json from request: {}", message);
+                Err(JsonBodyError::Invalid(message))
+            }
+        }
+    }
+
+    /// API paths and clients that ask for JSON get JSON error bodies, everything else gets HTML
+    pub fn accepts_json(&self) -> bool {
+        self.path.is_some_and(|path| path.starts_with("/api/"))
+            || self
+                .header("Accept")
+                .is_some_and(|accept| accept.contains("application/json"))
+    }
+
+    /// If a comma separated header like `Connection` has the token, ignoring case
+    pub fn header_has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value_token| value_token.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
+    /// If `Accept-Encoding` lists the encoding, or `*`, without `q=0`. A missing header
+    /// accepts nothing but the plain body
+    pub fn accepts_encoding(&self, encoding: &str) -> bool {
+        let Some(value) = self.header("Accept-Encoding") else {
+            return false;
+        };
+        // The encoding by name wins over `*` whichever comes first
+        let mut wildcard = false;
+        for item in value.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let accepted = !parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            if name.eq_ignore_ascii_case(encoding) {
+                return accepted;
+            }
+            if name == "*" {
+                wildcard = accepted;
+            }
+        }
+        wildcard
+    }
+
+    /// HTTP/1.1 connections stay open unless the client asks to close,
+    /// HTTP/1.0 connections close unless the client asks for keep-alive
+    pub fn keep_alive(&self) -> bool {
+        if self.header_has_token("Connection", "close") {
+            return false;
+        }
+        self.header_has_token("Connection", "keep-alive") || self.version >= 1
+    }
+
+    /// If the browser says the request came from a page served by this device. None for
+    /// clients like curl that send neither `Origin` nor `Referer`
+    pub fn is_same_origin(&self) -> Option<bool> {
+        let source = self.header("Origin").or_else(|| self.header("Referer"))?;
+        let Some(host) = self.header("Host") else {
+            return Some(false);
+        };
+        // `http://host:port` or `http://host:port/page`, a `null` origin never matches
+        let authority = source
+            .split_once("://")
+            .and_then(|(_, rest)| rest.split('/').next())
+            .unwrap_or("");
+        Some(authority.eq_ignore_ascii_case(host.trim()))
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.method == Some(Method::Get)
+            && self.header_has_token("Connection", "upgrade")
+            && self.header_has_token("Upgrade", "websocket")
+            && self.header("Sec-WebSocket-Key").is_some()
+            && self
+                .header("Sec-WebSocket-Version")
+                .is_some_and(|version| version.trim() == "13")
+    }
+}
+
+/// Body of every JSON error reply
+#[derive(Serialize)]
+pub struct ApiError<'a> {
+    pub error: &'a str,
+    pub message: &'a str,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JsonBodyError {
+    EmptyBody,
+    Invalid(&'static str),
+}
+
+impl JsonBodyError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::EmptyBody => "Request body is empty",
+            Self::Invalid(message) => message,
+        }
+    }
+}
+
+impl From<JsonBodyError> for WebRequestHandlerError {
+    fn from(err: JsonBodyError) -> Self {
+        Self::BadRequest(err.message())
+    }
+}
+
+fn json_error_message(error: serde_json_core::de::Error) -> &'static str {
+    use serde_json_core::de::Error;
+    match error {
+        Error::EofWhileParsingList
+        | Error::EofWhileParsingObject
+        | Error::EofWhileParsingString
+        | Error::EofWhileParsingNumber
+        | Error::EofWhileParsingValue => "Unexpected end of JSON",
+        Error::InvalidNumber => "Invalid number",
+        Error::InvalidType => "A field has the wrong type",
+        Error::EscapedStringIsTooLong => "A string is too long",
+        Error::TrailingCharacters => "Unexpected characters after the JSON value",
+        Error::CustomError => "Missing or unknown field",
+        _ => "Body is not valid JSON",
+    }
+}
+
+/// Errors a handler can return, the server turns them into the matching status code
+/// with a JSON or HTML body
+#[allow(dead_code)]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WebRequestHandlerError {
+    BadRequest(&'static str),
+    /// No credentials, the response asks the browser to log in
+    Unauthorized,
+    /// A password or token that's wrong. Also asks for a login, and counts towards banning
+    /// the client like an unparseable request does
+    BadCredentials,
+    /// Logged in but without enough access, or the admin password still has to be set
+    Forbidden(&'static str),
+    NotFound,
+    MethodNotAllowed,
+    Conflict(&'static str),
+    PayloadTooLarge,
+    /// The client is over a rate limit
+    TooManyRequests,
+    Internal(&'static str),
+    Unavailable(&'static str),
+}
+
+impl WebRequestHandlerError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BadRequest,
+            Self::Unauthorized | Self::BadCredentials => StatusCode::Unauthorized,
+            Self::Forbidden(_) => StatusCode::Forbidden,
+            Self::NotFound => StatusCode::NotFound,
+            Self::MethodNotAllowed => StatusCode::MethodNotAllowed,
+            Self::Conflict(_) => StatusCode::Conflict,
+            Self::PayloadTooLarge => StatusCode::PayloadTooLarge,
+            Self::TooManyRequests => StatusCode::TooManyRequests,
+            Self::Internal(_) => StatusCode::InternalServerError,
+            Self::Unavailable(_) => StatusCode::ServiceUnavailable,
+        }
+    }
+
+    /// Short machine readable name sent as the `error` field of JSON bodies
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthorized => "unauthorized",
+            Self::BadCredentials => "bad_credentials",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound => "not_found",
+            Self::MethodNotAllowed => "method_not_allowed",
+            Self::Conflict(_) => "conflict",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::TooManyRequests => "too_many_requests",
+            Self::Internal(_) => "internal",
+            Self::Unavailable(_) => "unavailable",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::BadRequest(message)
+            | Self::Forbidden(message)
+            | Self::Conflict(message)
+            | Self::Internal(message)
+            | Self::Unavailable(message) => message,
+            Self::Unauthorized => "Authentication required",
+            Self::BadCredentials => "Wrong password or token",
+            Self::NotFound => "Not found",
+            Self::MethodNotAllowed => "Method not allowed",
+            Self::PayloadTooLarge => "Request is too large",
+            Self::TooManyRequests => "Too many requests, slow down",
+        }
+    }
+
+    pub fn into_response<'a>(self, json: bool, response_buffer: &'a mut [u8]) -> Response<'a> {
+        if json {
+            return Response::new_json_error(
+                self.status_code(),
+                self.code(),
+                self.message(),
+                response_buffer,
+            );
+        }
+
+        let mut writer = BufWriter::new(response_buffer);
+        let written = fmt_write(
+            &mut writer,
+            format_args!(
+                "<!DOCTYPE html><html><body><h1>{}</h1><p>{}</p></body></html>",
+                self.status_code().as_str(),
+                self.message()
+            ),
+        );
+        let len = writer.len();
+        match written {
+            Ok(()) => {
+                let response_buffer: &'a [u8] = response_buffer;
+                Response::new_with_content_type(
+                    self.status_code(),
+                    "text/html; charset=utf-8",
+                    &response_buffer[..len],
+                )
+            }
+            Err(_) => Response::new_html(self.status_code(), self.message()),
+        }
+    }
+}
+
+/// Handlers are shared by every connection, so state that changes lives behind a `Mutex` or `Cell`
+pub trait WebRequestHandler {
+    async fn handle_request<'a>(
+        &'a self,
+        request: WebRequest<'_, '_>,
+        response_buffer: &'a mut [u8],
+    ) -> Result<Response<'a>, WebRequestHandlerError>;
+
+    /// Return true to accept a WebSocket upgrade request. Requests that aren't accepted
+    /// go to `handle_request` like any other
+    fn accepts_websocket(&self, _request: &WebRequest<'_, '_>) -> bool {
+        false
+    }
+
+    /// Runs for as long as an accepted WebSocket is open, the connection is closed when it returns
+    async fn handle_websocket<S: Read + Write>(&self, _websocket: &mut WebSocket<'_, S>) {}
+
+    /// Return true to let a state changing request from another site's page through, when it
+    /// carries a token that is valid for the route. Never count credentials the browser adds
+    /// by itself like a remembered Basic login, and never routes anyone can use, or any page
+    /// the visitor opens could use them. Only origins CORS lists by name get through otherwise
+    fn trusts_cross_origin(&self, _request: &WebRequest<'_, '_>) -> bool {
+        false
+    }
+}
+
+/// Serves a handler that other tasks use too, like `serve(stack, 80, &handler)`
+impl<H: WebRequestHandler> WebRequestHandler for &H {
+    async fn handle_request<'a>(
+        &'a self,
+        request: WebRequest<'_, '_>,
+        response_buffer: &'a mut [u8],
+    ) -> Result<Response<'a>, WebRequestHandlerError> {
+        (**self).handle_request(request, response_buffer).await
+    }
+
+    fn accepts_websocket(&self, request: &WebRequest<'_, '_>) -> bool {
+        (**self).accepts_websocket(request)
+    }
+
+    async fn handle_websocket<S: Read + Write>(&self, websocket: &mut WebSocket<'_, S>) {
+        (**self).handle_websocket(websocket).await
+    }
+
+    fn trusts_cross_origin(&self, request: &WebRequest<'_, '_>) -> bool {
+        (**self).trusts_cross_origin(request)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Delete,
+    Get,
+    Head,
+    Post,
+    Put,
+    Connect,
+    Options,
+    Trace,
+    Copy,
+    Lock,
+    MkCol,
+    Move,
+    Propfind,
+    Proppatch,
+    Search,
+    Unlock,
+    Bind,
+    Rebind,
+    Unbind,
+    Acl,
+    Report,
+    MkActivity,
+    Checkout,
+    Merge,
+    MSearch,
+    Notify,
+    Subscribe,
+    Unsubscribe,
+    Patch,
+    Purge,
+    MkCalendar,
+    Link,
+    Unlink,
+}
+
+impl Method {
+    /// Methods that shouldn't change anything on the server
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Self::Get | Self::Head | Self::Options | Self::Trace)
+    }
+
+    pub fn new(method: &str) -> Option<Self> {
+        if method.eq_ignore_ascii_case("Delete") {
+            Some(Self::Delete)
+        } else if method.eq_ignore_ascii_case("Get") {
+            Some(Self::Get)
+        } else if method.eq_ignore_ascii_case("Head") {
+            Some(Self::Head)
+        } else if method.eq_ignore_ascii_case("Post") {
+            Some(Self::Post)
+        } else if method.eq_ignore_ascii_case("Put") {
+            Some(Self::Put)
+        } else if method.eq_ignore_ascii_case("Connect") {
+            Some(Self::Connect)
+        } else if method.eq_ignore_ascii_case("Options") {
+            Some(Self::Options)
+        } else if method.eq_ignore_ascii_case("Trace") {
+            Some(Self::Trace)
+        } else if method.eq_ignore_ascii_case("Copy") {
+            Some(Self::Copy)
+        } else if method.eq_ignore_ascii_case("Lock") {
+            Some(Self::Lock)
+        } else if method.eq_ignore_ascii_case("MkCol") {
+            Some(Self::MkCol)
+        } else if method.eq_ignore_ascii_case("Move") {
+            Some(Self::Move)
+        } else if method.eq_ignore_ascii_case("Propfind") {
+            Some(Self::Propfind)
+        } else if method.eq_ignore_ascii_case("Proppatch") {
+            Some(Self::Proppatch)
+        } else if method.eq_ignore_ascii_case("Search") {
+            Some(Self::Search)
+        } else if method.eq_ignore_ascii_case("Unlock") {
+            Some(Self::Unlock)
+        } else if method.eq_ignore_ascii_case("Bind") {
+            Some(Self::Bind)
+        } else if method.eq_ignore_ascii_case("Rebind") {
+            Some(Self::Rebind)
+        } else if method.eq_ignore_ascii_case("Unbind") {
+            Some(Self::Unbind)
+        } else if method.eq_ignore_ascii_case("Acl") {
+            Some(Self::Acl)
+        } else if method.eq_ignore_ascii_case("Report") {
+            Some(Self::Report)
+        } else if method.eq_ignore_ascii_case("MkActivity") {
+            Some(Self::MkActivity)
+        } else if method.eq_ignore_ascii_case("Checkout") {
+            Some(Self::Checkout)
+        } else if method.eq_ignore_ascii_case("Merge") {
+            Some(Self::Merge)
+        } else if method.eq_ignore_ascii_case("MSearch") {
+            Some(Self::MSearch)
+        } else if method.eq_ignore_ascii_case("Notify") {
+            Some(Self::Notify)
+        } else if method.eq_ignore_ascii_case("Subscribe") {
+            Some(Self::Subscribe)
+        } else if method.eq_ignore_ascii_case("Unsubscribe") {
+            Some(Self::Unsubscribe)
+        } else if method.eq_ignore_ascii_case("Patch") {
+            Some(Self::Patch)
+        } else if method.eq_ignore_ascii_case("Purge") {
+            Some(Self::Purge)
+        } else if method.eq_ignore_ascii_case("MkCalendar") {
+            Some(Self::MkCalendar)
+        } else if method.eq_ignore_ascii_case("Link") {
+            Some(Self::Link)
+        } else if method.eq_ignore_ascii_case("Unlink") {
+            Some(Self::Unlink)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Delete => "DELETE",
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Connect => "CONNECT",
+            Self::Options => "OPTIONS",
+            Self::Trace => "TRACE",
+            Self::Copy => "COPY",
+            Self::Lock => "LOCK",
+            Self::MkCol => "MKCOL",
+            Self::Move => "MOVE",
+            Self::Propfind => "PROPFIND",
+            Self::Proppatch => "PROPPATCH",
+            Self::Search => "SEARCH",
+            Self::Unlock => "UNLOCK",
+            Self::Bind => "BIND",
+            Self::Rebind => "REBIND",
+            Self::Unbind => "UNBIND",
+            Self::Acl => "ACL",
+            Self::Report => "REPORT",
+            Self::MkActivity => "MKACTIVITY",
+            Self::Checkout => "CHECKOUT",
+            Self::Merge => "MERGE",
+            Self::MSearch => "MSEARCH",
+            Self::Notify => "NOTIFY",
+            Self::Subscribe => "SUBSCRIBE",
+            Self::Unsubscribe => "UNSUBSCRIBE",
+            Self::Patch => "PATCH",
+            Self::Purge => "PURGE",
+            Self::MkCalendar => "MKCALENDAR",
+            Self::Link => "LINK",
+            Self::Unlink => "UNLINK",
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum StatusCode {
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    MovedPermanently,
+    MovedTemporarily,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    TooManyRequests,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+}
+
+impl StatusCode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "200 OK",
+            Self::Created => "201 Created",
+            Self::Accepted => "202 Accepted",
+            Self::NoContent => "204 No Content",
+            Self::MovedPermanently => "301 Moved Permanently",
+            Self::MovedTemporarily => "302 Moved Temporarily",
+            Self::NotModified => "304 Not Modified",
+            Self::BadRequest => "400 Bad Request",
+            Self::Unauthorized => "401 Unauthorized",
+            Self::Forbidden => "403 Forbidden",
+            Self::NotFound => "404 Not Found",
+            Self::MethodNotAllowed => "405 Method Not Allowed",
+            Self::Conflict => "409 Conflict",
+            Self::PayloadTooLarge => "413 Payload Too Large",
+            Self::TooManyRequests => "429 Too Many Requests",
+            Self::InternalServerError => "500 Internal Server Error",
+            Self::NotImplemented => "501 Not Implemented",
+            Self::BadGateway => "502 Bad Gateway",
+            Self::ServiceUnavailable => "503 Service Unavailable",
+        }
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            Self::Ok => 200,
+            Self::Created => 201,
+            Self::Accepted => 202,
+            Self::NoContent => 204,
+            Self::MovedPermanently => 301,
+            Self::MovedTemporarily => 302,
+            Self::NotModified => 304,
+            Self::BadRequest => 400,
+            Self::Unauthorized => 401,
+            Self::Forbidden => 403,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::Conflict => 409,
+            Self::PayloadTooLarge => 413,
+            Self::TooManyRequests => 429,
+            Self::InternalServerError => 500,
+            Self::NotImplemented => 501,
+            Self::BadGateway => 502,
+            Self::ServiceUnavailable => 503,
+        }
+    }
+}
+
+/// A response header value, either a fixed string or one built at runtime like a length or an ETag
+pub enum HeaderValue {
+    Static(&'static str),
+    Owned(String<64>),
+}
+
+#[allow(dead_code)]
+impl HeaderValue {
+    /// Formats the value, returns None if it is longer than an owned value can hold
+    pub fn format(args: Arguments<'_>) -> Option<Self> {
+        let mut value = String::<64>::new();
+        match fmt_write(&mut value, args) {
+            Ok(()) => Some(Self::Owned(value)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Static(value) => value,
+            Self::Owned(value) => value.as_str(),
+        }
+    }
+}
+
+impl From<&'static str> for HeaderValue {
+    fn from(value: &'static str) -> Self {
+        Self::Static(value)
+    }
+}
+
+impl From<String<64>> for HeaderValue {
+    fn from(value: String<64>) -> Self {
+        Self::Owned(value)
+    }
+}
+
+type ResponseHeader = (&'static str, HeaderValue);
+/// Enough for caching, CORS and the `Connection` header on top of a handler's own
+const MAX_RESPONSE_HEADERS: usize = 12;
+
+/// Generates a response body piece by piece for content that is too big to build up front
+pub trait ChunkedBody {
+    /// Fills the buffer with the next piece of the body and returns how much was written.
+    /// Returning 0 ends the body
+    fn next_chunk(&mut self, buffer: &mut [u8]) -> usize;
+}
+
+// Only one response per connection exists at a time, so the size of the biggest variant is fine
+#[allow(clippy::large_enum_variant)]
+pub enum Body<'a> {
+    Empty,
+    /// Written to the socket straight from where it lives, so assets embedded in flash
+    /// with `include_bytes!` are never copied into RAM
+    Bytes(&'a [u8]),
+    /// Sent with chunked transfer encoding as it is generated
+    Chunked(&'a mut dyn ChunkedBody),
+    /// A `text/event-stream` of events from the event bus, starting with the optional event
+    EventStream(EventSubscriber, Option<ServerEvent>),
+}
+
+pub struct Response<'a> {
+    status_code: StatusCode,
+    body: Body<'a>,
+    headers: Vec<ResponseHeader, MAX_RESPONSE_HEADERS>,
+}
+
+#[allow(dead_code)]
+impl<'a> Response<'a> {
+    pub fn new(status_code: StatusCode, body: &'static str) -> Self {
+        Self {
+            status_code,
+            body: Body::Bytes(body.as_bytes()),
+            headers: Vec::new(),
+        }
+    }
+
+    /// A response with any content type and a binary body
+    pub fn new_with_content_type(
+        status_code: StatusCode,
+        content_type: &'static str,
+        body: &'a [u8],
+    ) -> Self {
+        let mut headers: Vec<ResponseHeader, MAX_RESPONSE_HEADERS> = Vec::new();
+        let _ = headers.push(("Content-type", HeaderValue::Static(content_type)));
+
+        Self {
+            status_code,
+            body: Body::Bytes(body),
+            headers,
+        }
+    }
+
+    /// Serializes the value into the buffer as the body. Falls back to a 500 if it doesn't fit
+    pub fn new_json_value<T: Serialize>(
+        status_code: StatusCode,
+        value: &T,
+        response_buffer: &'a mut [u8],
+    ) -> Self {
+        match serde_json_core::to_slice(value, response_buffer) {
+            Ok(len) => {
+                let response_buffer: &'a [u8] = response_buffer;
+                Self::new_with_content_type(
+                    status_code,
+                    "application/json",
+                    &response_buffer[..len],
+                )
+            }
+            Err(_) => {
+                warn!("JSON response did not fit in the response buffer");
+                Self::new_json(
+                    StatusCode::InternalServerError,
+                    r#"{"error":"internal","message":"Response too large"}"#,
+                )
+            }
+        }
+    }
+
+    /// A JSON error body clients can check the `error` field of
+    pub fn new_json_error(
+        status_code: StatusCode,
+        error: &str,
+        message: &str,
+        response_buffer: &'a mut [u8],
+    ) -> Self {
+        Self::new_json_value(status_code, &ApiError { error, message }, response_buffer)
+    }
+
+    pub fn new_html(status_code: StatusCode, body: &'a str) -> Self {
+        Self::new_with_content_type(status_code, "text/html; charset=utf-8", body.as_bytes())
+    }
+
+    pub fn new_json(status_code: StatusCode, body: &'a str) -> Self {
+        Self::new_with_content_type(status_code, "application/json", body.as_bytes())
+    }
+
+    pub fn new_text(status_code: StatusCode, body: &'a str) -> Self {
+        Self::new_with_content_type(status_code, "text/plain; charset=utf-8", body.as_bytes())
+    }
+
+    pub fn new_css(status_code: StatusCode, body: &'a str) -> Self {
+        Self::new_with_content_type(status_code, "text/css; charset=utf-8", body.as_bytes())
+    }
+
+    pub fn new_js(status_code: StatusCode, body: &'a str) -> Self {
+        Self::new_with_content_type(
+            status_code,
+            "text/javascript; charset=utf-8",
+            body.as_bytes(),
+        )
+    }
+
+    pub fn new_image(status_code: StatusCode, image_type: ImageType, body: &'a [u8]) -> Self {
+        Self::new_with_content_type(status_code, image_type.as_str(), body)
+    }
+
+    pub fn new_octet_stream(status_code: StatusCode, body: &'a [u8]) -> Self {
+        Self::new_with_content_type(status_code, "application/octet-stream", body)
+    }
+
+    /// A response with only a status line and headers, like a `204`
+    pub fn new_empty(status_code: StatusCode) -> Self {
+        Self {
+            status_code,
+            body: Body::Empty,
+            headers: Vec::new(),
+        }
+    }
+
+    /// A `302` to another page on the device
+    pub fn new_redirect(location: &'static str) -> Self {
+        let mut headers: Vec<ResponseHeader, MAX_RESPONSE_HEADERS> = Vec::new();
+        let _ = headers.push(("Location", HeaderValue::Static(location)));
+
+        Self {
+            status_code: StatusCode::MovedTemporarily,
+            body: Body::Empty,
+            headers,
+        }
+    }
+
+    pub fn new_chunked(
+        status_code: StatusCode,
+        content_type: &'static str,
+        body: &'a mut dyn ChunkedBody,
+    ) -> Self {
+        let mut headers: Vec<ResponseHeader, MAX_RESPONSE_HEADERS> = Vec::new();
+        let _ = headers.push(("Content-type", HeaderValue::Static(content_type)));
+
+        Self {
+            status_code,
+            body: Body::Chunked(body),
+            headers,
+        }
+    }
+
+    /// Streams Server-Sent Events until the client disconnects. Fails when every
+    /// event subscriber is already in use
+    pub fn new_event_stream(
+        initial_event: Option<ServerEvent>,
+    ) -> Result<Self, WebRequestHandlerError> {
+        let subscriber = EVENTS
+            .subscriber()
+            .map_err(|_| WebRequestHandlerError::Unavailable("Too many event streams are open"))?;
+        let mut headers: Vec<ResponseHeader, MAX_RESPONSE_HEADERS> = Vec::new();
+        let _ = headers.push(("Content-type", HeaderValue::Static("text/event-stream")));
+        let _ = headers.push(("Cache-Control", HeaderValue::Static("no-cache")));
+
+        Ok(Self {
+            status_code: StatusCode::Ok,
+            body: Body::EventStream(subscriber, initial_event),
+            headers,
+        })
+    }
+
+    pub fn is_event_stream(&self) -> bool {
+        matches!(self.body, Body::EventStream(..))
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.body, Body::Chunked(_))
+    }
+
+    pub fn has_header(&self, key: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(key))
+    }
+
+    /// Adds a header to the response, ignored if the header list is already full
+    pub fn add_header(&mut self, key: &'static str, value: impl Into<HeaderValue>) {
+        if self.headers.push((key, value.into())).is_err() {
+            warn!("Too many response headers, dropping {}", key);
+        }
+    }
+
+    /// Adds a header with a value built at runtime, ignored if the value does not fit
+    pub fn add_formatted_header(&mut self, key: &'static str, args: Arguments<'_>) {
+        match HeaderValue::format(args) {
+            Some(value) => self.add_header(key, value),
+            None => warn!("Header value too long, dropping {}", key),
+        }
+    }
+
+    /// Writes the status line and headers. The body is sent separately by the server so it
+    /// is not limited by the size of the buffer the head is written into.
+    /// `use_chunked_encoding` is false for HTTP/1.0 clients, which get a chunked body
+    /// unframed and rely on the connection closing to find the end
+    pub fn write_head<W>(
+        &self,
+        writer: &mut W,
+        use_chunked_encoding: bool,
+    ) -> Result<(), core::fmt::Error>
+    where
+        W: core::fmt::Write,
+    {
+        fmt_write(
+            writer,
+            format_args!("HTTP/1.1 {} \r\n", self.status_code.as_str(),),
+        )?;
+
+        for (key, value) in self.headers.iter() {
+            fmt_write(writer, format_args!("{}: {}\r\n", key, value.as_str()))?;
+        }
+        match self.body {
+            // These never have a body, and a 304 keeps the headers of the full response
+            Body::Empty
+                if matches!(
+                    self.status_code,
+                    StatusCode::NoContent | StatusCode::NotModified
+                ) => {}
+            // Keep-alive clients need the length to know where this response ends
+            Body::Empty => writer.write_str("Content-Length: 0\r\n")?,
+            Body::Bytes(body) => {
+                fmt_write(writer, format_args!("Content-Length: {}\r\n", body.len()))?
+            }
+            Body::Chunked(_) => {
+                if use_chunked_encoding {
+                    writer.write_str("Transfer-Encoding: chunked\r\n")?;
+                }
+            }
+            // Ends when the connection closes
+            Body::EventStream(..) => {}
+        }
+        writer.write_str("\r\n")?;
+
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
+pub enum ImageType {
+    Png,
+    Jpeg,
+    Gif,
+    Svg,
+    Icon,
+    Webp,
+}
+
+impl ImageType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Gif => "image/gif",
+            Self::Svg => "image/svg+xml",
+            Self::Icon => "image/x-icon",
+            Self::Webp => "image/webp",
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Per-client limits on how often something can be done, like sending robot commands
+use core::cell::RefCell;
+use core::net::IpAddr;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// Lets each IP do `burst` things at once, then one every `interval`. Only the last `N`
+/// clients are tracked, which is plenty for a device on a home network. Requests whose
+/// address isn't known share one allowance
+pub struct RateLimiter<const N: usize> {
+    burst: u32,
+    interval: Duration,
+    clients: Mutex<CriticalSectionRawMutex, RefCell<Vec<Client, N>>>,
+}
+
+struct Client {
+    ip: Option<IpAddr>,
+    /// When the allowance is all back, later means less of it is left
+    full_at: Instant,
+}
+
+impl<const N: usize> RateLimiter<N> {
+    pub const fn new(burst: u32, interval: Duration) -> Self {
+        Self {
+            burst,
+            interval,
+            clients: Mutex::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Uses up one of the client's allowance, false when there is none left
+    pub fn check(&self, ip: Option<IpAddr>) -> bool {
+        self.check_n(ip, 1)
+    }
+
+    /// Uses up `n` of the client's allowance at once, for requests that do `n` things. False
+    /// and nothing used when there's less than `n` left, so more than `burst` never passes
+    pub fn check_n(&self, ip: Option<IpAddr>, n: u32) -> bool {
+        if n == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        // Only the part of the allowance past the first request counts as slack
+        let slack = self.interval * self.burst.saturating_sub(1);
+        self.clients.lock(|clients| {
+            let mut clients = clients.borrow_mut();
+            let index = match clients.iter().position(|client| client.ip == ip) {
+                Some(index) => index,
+                None => {
+                    if clients.is_full() {
+                        // Whoever would have their allowance back soonest is forgotten
+                        let oldest = (0..clients.len())
+                            .min_by_key(|&i| clients[i].full_at)
+                            .unwrap_or(0);
+                        clients.swap_remove(oldest);
+                    }
+                    let _ = clients.push(Client { ip, full_at: now });
+                    clients.len() - 1
+                }
+            };
+
+            let full_at = clients[index].full_at.max(now);
+            if full_at + self.interval * (n - 1) > now + slack {
+                return false;
+            }
+            clients[index].full_at = full_at + self.interval * n;
+            true
+        })
+    }
+}
@@ -0,0 +1,69 @@
+//! Serves connections accepted on embassy-net TCP sockets
+use core::net::SocketAddr;
+use embassy_futures::join::join_array;
+use embassy_net::{tcp::TcpSocket, IpEndpoint, Stack};
+use embassy_time::Duration;
+
+use crate::io::try_format;
+use crate::{ConnectionBuffers, HttpServer, WebRequestHandler, HTTP_SOCKETS};
+
+/// Backstop for a client that stops reading what is written to it. Reads have their own
+/// shorter timeouts, so this only has to outlast the WebSocket one
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(90);
+
+impl HttpServer {
+    /// Serves `HTTP_SOCKETS` connections at once so long lived event streams and WebSockets
+    /// don't block normal requests. Every connection shares the one handler
+    pub async fn serve<H>(&self, stack: Stack<'static>, port: u16, handler: H)
+    where
+        H: WebRequestHandler,
+    {
+        info!("Listening on port {} with {} sockets", port, HTTP_SOCKETS);
+        let handler = &handler;
+        join_array(core::array::from_fn::<_, HTTP_SOCKETS, _>(|socket_id| {
+            self.serve_socket(stack, port, socket_id, handler)
+        }))
+        .await;
+    }
+
+    async fn serve_socket<H>(&self, stack: Stack<'static>, port: u16, socket_id: usize, handler: &H)
+    where
+        H: WebRequestHandler,
+    {
+        let mut rx_buffer = [0; 2_048];
+        let mut tx_buffer = [0; 4_096];
+        let mut buffers = ConnectionBuffers::new();
+        loop {
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(SOCKET_TIMEOUT));
+
+            if let Err(e) = socket.accept(port).await {
+                warn!("accept error: {:?}", e);
+                continue;
+            }
+
+            let remote = socket.remote_endpoint();
+            info!("Socket {} received connection from {:?}", socket_id, remote);
+
+            self.serve_connection(
+                &mut socket,
+                remote.and_then(socket_addr),
+                handler,
+                &mut buffers,
+            )
+            .await;
+
+            //Have to close the socket so the web browser knows its done
+            socket.close();
+            let _ = socket.flush().await;
+        }
+    }
+}
+
+/// Goes through the text form, which is the same whichever address type embassy-net uses
+fn socket_addr(endpoint: IpEndpoint) -> Option<SocketAddr> {
+    try_format::<48>(format_args!("{}", endpoint))
+        .ok()?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,85 @@
+//! Limits on how long each part of a request can take, so a client that opens a
+//! connection and then trickles bytes or stops reading can't hold a socket for long
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, Write};
+
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    /// An idle keep-alive connection waiting for its next request
+    pub keep_alive: Duration,
+    /// From the connection opening, or a later request's first byte, until all its headers are in
+    pub header: Duration,
+    /// From the headers being in until the whole body is
+    pub body: Duration,
+    /// Each write of the response, including every event and WebSocket frame
+    pub write: Duration,
+}
+
+impl Timeouts {
+    pub const DEFAULT: Self = Self {
+        keep_alive: Duration::from_secs(5),
+        header: Duration::from_secs(5),
+        body: Duration::from_secs(10),
+        write: Duration::from_secs(10),
+    };
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Debug)]
+pub enum TimeoutError<E> {
+    TimedOut,
+    Io(E),
+}
+
+impl<E: Error> Error for TimeoutError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::TimedOut => ErrorKind::TimedOut,
+            Self::Io(e) => e.kind(),
+        }
+    }
+}
+
+/// Gives every write and flush on the stream its own timeout. Reads are passed straight through
+/// since the server times those by phase
+pub struct WriteTimeout<'s, S> {
+    stream: &'s mut S,
+    timeout: Duration,
+}
+
+impl<'s, S> WriteTimeout<'s, S> {
+    pub fn new(stream: &'s mut S, timeout: Duration) -> Self {
+        Self { stream, timeout }
+    }
+}
+
+impl<S: ErrorType> ErrorType for WriteTimeout<'_, S> {
+    type Error = TimeoutError<S::Error>;
+}
+
+impl<S: Read> Read for WriteTimeout<'_, S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.stream.read(buf).await.map_err(TimeoutError::Io)
+    }
+}
+
+impl<S: Write> Write for WriteTimeout<'_, S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        with_timeout(self.timeout, self.stream.write(buf))
+            .await
+            .map_err(|_| TimeoutError::TimedOut)?
+            .map_err(TimeoutError::Io)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        with_timeout(self.timeout, self.stream.flush())
+            .await
+            .map_err(|_| TimeoutError::TimedOut)?
+            .map_err(TimeoutError::Io)
+    }
+}
@@ -0,0 +1,126 @@
+//! Zero allocation parsing of `application/x-www-form-urlencoded` data, used for both
+//! query strings and form bodies. Values are decoded lazily as they are read
+use core::str::FromStr;
+use heapless::String;
+
+/// Iterates over the `key=value` pairs of a query string or form body
+#[derive(Clone)]
+pub struct UrlEncodedParams<'a> {
+    remaining: &'a str,
+}
+
+impl<'a> UrlEncodedParams<'a> {
+    pub fn new(encoded: &'a str) -> Self {
+        Self { remaining: encoded }
+    }
+
+    /// First value for the key
+    pub fn get(&self, key: &str) -> Option<UrlEncoded<'a>> {
+        self.clone()
+            .find(|(name, _)| name.eq_str(key))
+            .map(|(_, value)| value)
+    }
+}
+
+impl<'a> Iterator for UrlEncodedParams<'a> {
+    type Item = (UrlEncoded<'a>, UrlEncoded<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.remaining.is_empty() {
+                return None;
+            }
+            let (pair, rest) = match self.remaining.split_once('&') {
+                Some((pair, rest)) => (pair, rest),
+                None => (self.remaining, ""),
+            };
+            self.remaining = rest;
+            // Skip the empty pairs from things like `a=1&&b=2`
+            if pair.is_empty() {
+                continue;
+            }
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            return Some((UrlEncoded(key), UrlEncoded(value)));
+        }
+    }
+}
+
+/// A still encoded key or value
+#[derive(Clone, Copy)]
+pub struct UrlEncoded<'a>(&'a str);
+
+#[allow(dead_code)]
+impl<'a> UrlEncoded<'a> {
+    pub fn raw(&self) -> &'a str {
+        self.0
+    }
+
+    pub fn decoded_bytes(&self) -> DecodedBytes<'a> {
+        DecodedBytes {
+            bytes: self.0.as_bytes(),
+        }
+    }
+
+    /// Compares the decoded value without decoding it anywhere
+    pub fn eq_str(&self, other: &str) -> bool {
+        self.decoded_bytes().eq(other.bytes())
+    }
+
+    /// Decodes into the buffer, None if it doesn't fit or isn't valid UTF-8
+    pub fn decode_into<'b>(&self, buffer: &'b mut [u8]) -> Option<&'b str> {
+        let mut len = 0;
+        for byte in self.decoded_bytes() {
+            *buffer.get_mut(len)? = byte;
+            len += 1;
+        }
+        core::str::from_utf8(&buffer[..len]).ok()
+    }
+
+    pub fn to_string<const N: usize>(&self) -> Option<String<N>> {
+        let mut buffer = [0u8; N];
+        let decoded = self.decode_into(&mut buffer)?;
+        String::try_from(decoded).ok()
+    }
+
+    /// Parses short values like numbers and flags
+    pub fn parse<T: FromStr>(&self) -> Option<T> {
+        let mut buffer = [0u8; 32];
+        self.decode_into(&mut buffer)?.parse::<T>().ok()
+    }
+}
+
+/// Decodes `+` to a space and `%XX` to its byte. Malformed escapes are passed through as is
+pub struct DecodedBytes<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for DecodedBytes<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        match byte {
+            b'+' => Some(b' '),
+            b'%' => {
+                if let [high, low, rest @ ..] = self.bytes {
+                    if let (Some(high), Some(low)) = (hex_value(*high), hex_value(*low)) {
+                        self.bytes = rest;
+                        return Some(high << 4 | low);
+                    }
+                }
+                Some(b'%')
+            }
+            _ => Some(byte),
+        }
+    }
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
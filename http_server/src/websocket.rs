@@ -0,0 +1,368 @@
+//! RFC 6455 WebSockets on top of a connection the `HttpServer` has upgraded.
+//! Only unfragmented messages that fit in the read buffer are supported, which is
+//! everything a browser sends for small control messages
+use base64::{engine::general_purpose::STANDARD, Engine};
+use core::net::SocketAddr;
+use core::str;
+use embassy_time::{with_timeout, Duration, Instant};
+use embedded_io_async::{ErrorKind, Read, Write};
+use heapless::String;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+/// Longest payload a ping, pong or close frame can have
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Close status codes from RFC 6455 section 7.4.1
+#[allow(dead_code)]
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const TRY_AGAIN_LATER: u16 = 1013;
+}
+
+/// The `Sec-WebSocket-Accept` value for the client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String<28> {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(WEBSOCKET_GUID);
+    let hash = hasher.finalize();
+
+    let mut encoded = [0u8; 28];
+    let mut accept = String::new();
+    // 20 bytes of SHA-1 is always 28 characters of base64
+    if let Ok(len) = STANDARD.encode_slice(hash, &mut encoded) {
+        if let Ok(encoded) = str::from_utf8(&encoded[..len]) {
+            let _ = accept.push_str(encoded);
+        }
+    }
+    accept
+}
+
+/// Sends the 101 reply that switches the connection over to WebSocket frames
+pub async fn write_handshake<S: Write>(stream: &mut S, accept: &str) -> Result<(), S::Error> {
+    stream
+        .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: ")
+        .await?;
+    stream.write_all(accept.as_bytes()).await?;
+    stream.write_all(b"\r\n\r\n").await?;
+    stream.flush().await
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WebSocketError {
+    /// The client closed the connection or sent a close frame
+    Closed,
+    /// Nothing arrived from the client within the timeout
+    TimedOut,
+    Protocol,
+    InvalidUtf8,
+    MessageTooBig,
+    Io(ErrorKind),
+}
+
+fn io_error<E: embedded_io_async::Error>(err: E) -> WebSocketError {
+    WebSocketError::Io(err.kind())
+}
+
+pub enum Message<'m> {
+    Text(&'m str),
+    Binary(&'m [u8]),
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl FrameHeader {
+    /// None until enough bytes have arrived to know the whole header
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let fin = bytes[0] & 0x80 != 0;
+        let opcode = bytes[0] & 0x0F;
+        let masked = bytes[1] & 0x80 != 0;
+        let (payload_len, mut header_len) = match bytes[1] & 0x7F {
+            126 => {
+                if bytes.len() < 4 {
+                    return None;
+                }
+                (u16::from_be_bytes([bytes[2], bytes[3]]) as usize, 4)
+            }
+            127 => {
+                if bytes.len() < 10 {
+                    return None;
+                }
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&bytes[2..10]);
+                // Anything that doesn't fit in usize is far too big for the buffer anyway
+                (
+                    usize::try_from(u64::from_be_bytes(len)).unwrap_or(usize::MAX),
+                    10,
+                )
+            }
+            len => (len as usize, 2),
+        };
+        let mask = if masked {
+            if bytes.len() < header_len + 4 {
+                return None;
+            }
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&bytes[header_len..header_len + 4]);
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Some(Self {
+            fin,
+            opcode,
+            mask,
+            header_len,
+            payload_len,
+        })
+    }
+
+    fn frame_len(&self) -> usize {
+        self.header_len.saturating_add(self.payload_len)
+    }
+}
+
+pub struct WebSocket<'s, S> {
+    stream: &'s mut S,
+    buffer: &'s mut [u8],
+    /// Bytes in the buffer read from the socket
+    filled: usize,
+    /// Bytes at the front of the buffer that belong to the message handed out last
+    consumed: usize,
+    closed: bool,
+    last_received: Instant,
+    /// Longest a read waits for the client before giving up on it
+    timeout: Option<Duration>,
+    remote: Option<SocketAddr>,
+}
+
+#[allow(dead_code)]
+impl<'s, S: Read + Write> WebSocket<'s, S> {
+    /// `filled` is how many bytes at the front of the buffer already arrived after the handshake
+    pub fn new(stream: &'s mut S, buffer: &'s mut [u8], filled: usize) -> Self {
+        Self {
+            stream,
+            buffer,
+            filled,
+            consumed: 0,
+            closed: false,
+            last_received: Instant::now(),
+            timeout: None,
+            remote: None,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_remote(&mut self, remote: Option<SocketAddr>) {
+        self.remote = remote;
+    }
+
+    /// Who is on the other end, when the connection knows
+    pub fn remote(&self) -> Option<SocketAddr> {
+        self.remote
+    }
+
+    /// When any frame, including a pong, was last received. Used to spot dead clients
+    pub fn last_received(&self) -> Instant {
+        self.last_received
+    }
+
+    /// Waits for the next text or binary message. Pings are answered and pongs skipped while waiting.
+    /// Returns `WebSocketError::Closed` once the client closes the connection
+    pub async fn read(&mut self) -> Result<Message<'_>, WebSocketError> {
+        loop {
+            if self.closed {
+                return Err(WebSocketError::Closed);
+            }
+
+            // Drop the message handed out by the last call
+            if self.consumed > 0 {
+                self.buffer.copy_within(self.consumed..self.filled, 0);
+                self.filled -= self.consumed;
+                self.consumed = 0;
+            }
+
+            let header = loop {
+                match FrameHeader::parse(&self.buffer[..self.filled]) {
+                    Some(header) if header.frame_len() > self.buffer.len() => {
+                        warn!("WebSocket frame of {} bytes is too big", header.payload_len);
+                        self.close(close_code::MESSAGE_TOO_BIG).await;
+                        return Err(WebSocketError::MessageTooBig);
+                    }
+                    Some(header) if header.frame_len() <= self.filled => break header,
+                    _ => self.fill().await?,
+                }
+            };
+            self.last_received = Instant::now();
+            self.consumed = header.frame_len();
+            let payload = header.header_len..header.frame_len();
+
+            // Clients have to mask every frame they send
+            let Some(mask) = header.mask else {
+                self.close(close_code::PROTOCOL_ERROR).await;
+                return Err(WebSocketError::Protocol);
+            };
+            for (i, byte) in self.buffer[payload.clone()].iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            if !header.fin || header.opcode == OPCODE_CONTINUATION {
+                warn!("Fragmented WebSocket messages are not supported");
+                self.close(close_code::UNSUPPORTED_DATA).await;
+                return Err(WebSocketError::Protocol);
+            }
+
+            // Control frames are never bigger than this, anything else is a broken client
+            if header.opcode & 0x8 != 0 && header.payload_len > MAX_CONTROL_PAYLOAD {
+                self.close(close_code::PROTOCOL_ERROR).await;
+                return Err(WebSocketError::Protocol);
+            }
+
+            match header.opcode {
+                OPCODE_TEXT => {
+                    if str::from_utf8(&self.buffer[payload.clone()]).is_err() {
+                        self.close(close_code::INVALID_PAYLOAD).await;
+                        return Err(WebSocketError::InvalidUtf8);
+                    }
+                    let text = str::from_utf8(&self.buffer[payload])
+                        .map_err(|_| WebSocketError::InvalidUtf8)?;
+                    return Ok(Message::Text(text));
+                }
+                OPCODE_BINARY => return Ok(Message::Binary(&self.buffer[payload])),
+                OPCODE_PING => {
+                    let mut ping_payload = [0u8; MAX_CONTROL_PAYLOAD];
+                    let len = payload.len();
+                    ping_payload[..len].copy_from_slice(&self.buffer[payload]);
+                    self.write_frame(OPCODE_PONG, &ping_payload[..len]).await?;
+                }
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    // Echo the status code back to finish the closing handshake
+                    let code = match self.buffer[payload] {
+                        [high, low, ..] => u16::from_be_bytes([high, low]),
+                        _ => close_code::NORMAL,
+                    };
+                    self.close(code).await;
+                    return Err(WebSocketError::Closed);
+                }
+                _ => {
+                    self.close(close_code::PROTOCOL_ERROR).await;
+                    return Err(WebSocketError::Protocol);
+                }
+            }
+        }
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> Result<(), WebSocketError> {
+        self.write_frame(OPCODE_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.write_frame(OPCODE_BINARY, data).await
+    }
+
+    /// Serializes the value into the buffer and sends it as a text message
+    pub async fn send_json<T: Serialize>(
+        &mut self,
+        value: &T,
+        buffer: &mut [u8],
+    ) -> Result<(), WebSocketError> {
+        let len = match serde_json_core::to_slice(value, buffer) {
+            Ok(len) => len,
+            Err(_) => {
+                warn!("WebSocket message did not fit in the buffer");
+                return Err(WebSocketError::MessageTooBig);
+            }
+        };
+        self.write_frame(OPCODE_TEXT, &buffer[..len]).await
+    }
+
+    pub async fn ping(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        self.write_frame(OPCODE_PING, payload).await
+    }
+
+    /// Sends a close frame, only the first call does anything
+    pub async fn close(&mut self, code: u16) {
+        if self.closed {
+            return;
+        }
+        let _ = self.write_frame(OPCODE_CLOSE, &code.to_be_bytes()).await;
+        let _ = self.stream.flush().await;
+        self.closed = true;
+    }
+
+    async fn fill(&mut self) -> Result<(), WebSocketError> {
+        let read = self.stream.read(&mut self.buffer[self.filled..]);
+        let n = match self.timeout {
+            Some(timeout) => with_timeout(timeout, read)
+                .await
+                .map_err(|_| WebSocketError::TimedOut)?,
+            None => read.await,
+        }
+        .map_err(io_error)?;
+        if n == 0 {
+            self.closed = true;
+            return Err(WebSocketError::Closed);
+        }
+        self.filled += n;
+        Ok(())
+    }
+
+    /// Server frames are never masked or fragmented
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.closed {
+            return Err(WebSocketError::Closed);
+        }
+        let mut header = [0u8; 10];
+        header[0] = 0x80 | opcode;
+        let header_len = match payload.len() {
+            len if len < 126 => {
+                header[1] = len as u8;
+                2
+            }
+            len if len <= u16::MAX as usize => {
+                header[1] = 126;
+                header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+                4
+            }
+            len => {
+                header[1] = 127;
+                header[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+                10
+            }
+        };
+        self.stream
+            .write_all(&header[..header_len])
+            .await
+            .map_err(io_error)?;
+        self.stream.write_all(payload).await.map_err(io_error)?;
+        self.stream.flush().await.map_err(io_error)
+    }
+}
@@ -0,0 +1,873 @@
+//! Runs whole connections through the server against in-memory streams.
+//! Run with `cargo host-test` from the repo root
+use std::collections::VecDeque;
+use std::convert::Infallible;
+
+use embassy_futures::block_on;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{ErrorType, Read, Write};
+use http_server::access_log::{AccessLog, ACCESS_LOG_LEN};
+use http_server::cors::Cors;
+use http_server::rate_limit::RateLimiter;
+use http_server::timeouts::Timeouts;
+use http_server::websocket::{Message, WebSocket};
+use http_server::{
+    ChunkedBody, ConnectionBuffers, HttpServer, Method, Response, StatusCode, WebRequest,
+    WebRequestHandler, WebRequestHandlerError, REQUEST_BUFFER_LEN,
+};
+use serde::Deserialize;
+
+/// Hands out the reads it was given one at a time, then EOF. Everything written is kept
+struct MockStream {
+    reads: VecDeque<Vec<u8>>,
+    written: Vec<u8>,
+    /// Waited before each read to play a slow client
+    read_delay: Duration,
+    /// Writes never finish, like a client that stopped reading
+    stall_writes: bool,
+}
+
+impl MockStream {
+    fn new<I, R>(reads: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<[u8]>,
+    {
+        Self {
+            reads: reads
+                .into_iter()
+                .map(|read| read.as_ref().to_vec())
+                .collect(),
+            written: Vec::new(),
+            read_delay: Duration::from_ticks(0),
+            stall_writes: false,
+        }
+    }
+}
+
+impl ErrorType for MockStream {
+    type Error = Infallible;
+}
+
+impl Read for MockStream {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        if self.read_delay.as_ticks() > 0 {
+            Timer::after(self.read_delay).await;
+        }
+        let Some(mut read) = self.reads.pop_front() else {
+            return Ok(0);
+        };
+        // A read bigger than the buffer is handed out over several calls
+        let n = read.len().min(buf.len());
+        buf[..n].copy_from_slice(&read[..n]);
+        if n < read.len() {
+            self.reads.push_front(read.split_off(n));
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        if self.stall_writes {
+            core::future::pending::<()>().await;
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+#[derive(Deserialize)]
+struct Greeting<'a> {
+    name: &'a str,
+}
+
+struct Counter {
+    remaining: usize,
+}
+
+impl ChunkedBody for Counter {
+    fn next_chunk(&mut self, buffer: &mut [u8]) -> usize {
+        if self.remaining == 0 {
+            return 0;
+        }
+        self.remaining -= 1;
+        buffer[..5].copy_from_slice(b"tick\n");
+        5
+    }
+}
+
+struct TestHandler;
+
+impl WebRequestHandler for TestHandler {
+    async fn handle_request<'a>(
+        &'a self,
+        request: WebRequest<'_, '_>,
+        response_buffer: &'a mut [u8],
+    ) -> Result<Response<'a>, WebRequestHandlerError> {
+        match (request.method, request.path.unwrap_or("")) {
+            (Some(Method::Get), "/hello") => Ok(Response::new_text(StatusCode::Ok, "Hello")),
+            (Some(Method::Post), "/echo") => {
+                let len = request.body.len();
+                response_buffer[..len].copy_from_slice(request.body);
+                let response_buffer: &'a [u8] = response_buffer;
+                Ok(Response::new_octet_stream(
+                    StatusCode::Ok,
+                    &response_buffer[..len],
+                ))
+            }
+            (Some(Method::Post), "/greet") => {
+                let greeting = request.json::<Greeting>()?;
+                let text = http_server::io::try_format_str(
+                    format_args!("Hello {}", greeting.name),
+                    response_buffer,
+                )
+                .map_err(|_| WebRequestHandlerError::PayloadTooLarge)?;
+                Ok(Response::new_text(StatusCode::Ok, text))
+            }
+            (Some(Method::Get), "/private") => Err(WebRequestHandlerError::Unauthorized),
+            (Some(Method::Get), "/login") => Err(WebRequestHandlerError::BadCredentials),
+            (Some(Method::Get), "/chunked") => {
+                // Leaks a few bytes per test run, the body has to outlive the handler
+                let counter = Box::leak(Box::new(Counter { remaining: 3 }));
+                Ok(Response::new_chunked(StatusCode::Ok, "text/plain", counter))
+            }
+            (Some(Method::Get), "/gzip") if request.accepts_encoding("gzip") => {
+                Ok(Response::new_text(StatusCode::Ok, "gzip"))
+            }
+            (Some(Method::Get), "/gzip") => Ok(Response::new_text(StatusCode::Ok, "identity")),
+            _ => Err(WebRequestHandlerError::NotFound),
+        }
+    }
+
+    fn accepts_websocket(&self, request: &WebRequest<'_, '_>) -> bool {
+        request.path == Some("/ws")
+    }
+
+    /// `good` is the only real token, and only in a header or a query without a login
+    fn trusts_cross_origin(&self, request: &WebRequest<'_, '_>) -> bool {
+        match request.header("Authorization") {
+            Some(authorization) => authorization == "Bearer good",
+            None => request
+                .query_param("access_token")
+                .is_some_and(|token| token.eq_str("good")),
+        }
+    }
+
+    /// Echoes text messages back until the client closes
+    async fn handle_websocket<S: Read + Write>(&self, websocket: &mut WebSocket<'_, S>) {
+        let mut reply = [0u8; 64];
+        loop {
+            let len = match websocket.read().await {
+                Ok(Message::Text(text)) => {
+                    reply[..text.len()].copy_from_slice(text.as_bytes());
+                    text.len()
+                }
+                Ok(Message::Binary(_)) => continue,
+                Err(_) => return,
+            };
+            let text = core::str::from_utf8(&reply[..len]).unwrap();
+            if websocket.send_text(text).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+const CORS: Cors = Cors {
+    allowed_origins: &["http://panel.local"],
+    allowed_methods: "GET, POST, OPTIONS",
+    allowed_headers: "Content-Type",
+    allow_credentials: false,
+    max_age_secs: 600,
+};
+
+/// Serves one connection that receives `reads` and returns everything the server wrote
+fn serve_raw<I, R>(server: &HttpServer, reads: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = R>,
+    R: AsRef<[u8]>,
+{
+    let mut stream = MockStream::new(reads);
+    let mut buffers = Box::new(ConnectionBuffers::new());
+    block_on(server.serve_connection(&mut stream, None, &TestHandler, &mut buffers));
+    stream.written
+}
+
+fn serve_with<I, R>(server: &HttpServer, reads: I) -> String
+where
+    I: IntoIterator<Item = R>,
+    R: AsRef<[u8]>,
+{
+    String::from_utf8(serve_raw(server, reads)).unwrap()
+}
+
+fn serve<I, R>(reads: I) -> String
+where
+    I: IntoIterator<Item = R>,
+    R: AsRef<[u8]>,
+{
+    serve_with(&HttpServer::new(), reads)
+}
+
+/// Status line of every response, bodies aren't followed by a line break so this can't split lines
+fn status_lines(output: &str) -> Vec<&str> {
+    output
+        .match_indices("HTTP/1.1 ")
+        .map(|(start, _)| {
+            let line = &output[start..];
+            line[..line.find("\r\n").unwrap_or(line.len())].trim_end()
+        })
+        .collect()
+}
+
+fn body(output: &str) -> &str {
+    output.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+#[test]
+fn simple_get() {
+    let output = serve(["GET /hello HTTP/1.1\r\nHost: device\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert!(output.contains("Content-Length: 5\r\n"));
+    assert!(output.contains("Connection: keep-alive\r\n"));
+    assert_eq!(body(&output), "Hello");
+}
+
+#[test]
+fn handler_served_by_reference() {
+    let mut stream = MockStream::new(["GET /hello HTTP/1.1\r\nHost: device\r\n\r\n"]);
+    let mut buffers = Box::new(ConnectionBuffers::new());
+    let handler = &TestHandler;
+    block_on(HttpServer::new().serve_connection(&mut stream, None, &handler, &mut buffers));
+    let output = String::from_utf8(stream.written).unwrap();
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+}
+
+#[test]
+fn unknown_path_is_not_found() {
+    let output = serve(["GET /missing HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 404 Not Found"]);
+}
+
+#[test]
+fn request_split_into_single_bytes() {
+    let request = b"GET /hello HTTP/1.1\r\nHost: device\r\n\r\n";
+    let output = serve(request.iter().map(|byte| [*byte]));
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert_eq!(body(&output), "Hello");
+}
+
+#[test]
+fn body_arrives_after_headers() {
+    let output = serve([
+        "POST /echo HTTP/1.1\r\nContent-Length: 11\r\n\r\n",
+        "hello",
+        " world",
+    ]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert_eq!(body(&output), "hello world");
+}
+
+#[test]
+fn pipelined_requests_in_one_read() {
+    let output = serve([
+        "GET /hello HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\n\r\n",
+    ]);
+    assert_eq!(
+        status_lines(&output),
+        [
+            "HTTP/1.1 200 OK",
+            "HTTP/1.1 404 Not Found",
+            "HTTP/1.1 200 OK"
+        ]
+    );
+}
+
+#[test]
+fn second_request_split_across_reads() {
+    let output = serve([
+        "GET /hello HTTP/1.1\r\n\r\nGET /hel",
+        "lo HTTP/1.1\r\nConnection: close\r\n\r\n",
+    ]);
+    assert_eq!(
+        status_lines(&output),
+        ["HTTP/1.1 200 OK", "HTTP/1.1 200 OK"]
+    );
+    assert!(output.ends_with("Connection: close\r\nContent-Length: 5\r\n\r\nHello"));
+}
+
+#[test]
+fn garbage_is_a_bad_request() {
+    let output = serve([b"\x00\x01\x02 not http at all\r\n\r\n".as_slice()]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+    assert!(output.contains("Connection: close\r\n"));
+}
+
+#[test]
+fn bad_request_closes_before_later_requests() {
+    let output = serve(["GET / HTTP/9.9\r\n\r\nGET /hello HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+}
+
+#[test]
+fn invalid_content_length() {
+    let output = serve(["POST /echo HTTP/1.1\r\nContent-Length: ten\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+}
+
+#[test]
+fn chunked_request_body_is_rejected() {
+    let output =
+        serve(["POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+}
+
+#[test]
+fn oversized_body_is_refused_without_reading_it() {
+    let output = serve(["POST /echo HTTP/1.1\r\nContent-Length: 100000\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 413 Payload Too Large"]);
+    assert!(output.contains("Connection: close\r\n"));
+}
+
+#[test]
+fn content_length_overflow_is_refused() {
+    let output = serve(["POST /echo HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 413 Payload Too Large"]);
+}
+
+#[test]
+fn body_filling_the_buffer_exactly_is_handled() {
+    let head = format!("POST /echo HTTP/1.1\r\nContent-Length: {}\r\n\r\n", 2_000);
+    let output = serve([head.as_bytes(), &[b'a'; 2_000]]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert_eq!(body(&output).len(), 2_000);
+    assert!(head.len() + 2_000 < REQUEST_BUFFER_LEN);
+}
+
+#[test]
+fn oversized_headers_are_refused() {
+    let request = format!(
+        "GET /hello HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+        "a".repeat(REQUEST_BUFFER_LEN)
+    );
+    let output = serve([request]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 413 Payload Too Large"]);
+}
+
+#[test]
+fn too_many_headers_is_a_bad_request() {
+    let mut request = String::from("GET /hello HTTP/1.1\r\n");
+    for i in 0..30 {
+        request.push_str(&format!("X-Header-{}: {}\r\n", i, i));
+    }
+    request.push_str("\r\n");
+    let output = serve([request]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+}
+
+#[test]
+fn json_body() {
+    let output = serve([
+        "POST /greet HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 16\r\n\r\n{\"name\":\"robot\"}",
+    ]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert_eq!(body(&output), "Hello robot");
+}
+
+#[test]
+fn invalid_json_body_gets_a_json_error() {
+    let output = serve([
+        "POST /greet HTTP/1.1\r\nAccept: application/json\r\nContent-Length: 9\r\n\r\n{\"name\":1",
+    ]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+    assert!(body(&output).starts_with("{\"error\":\"bad_request\""));
+}
+
+#[test]
+fn empty_json_body() {
+    let output = serve(["POST /greet HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+    assert!(body(&output).contains("Request body is empty"));
+}
+
+#[test]
+fn head_has_headers_but_no_body() {
+    let output = serve(["HEAD /hello HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert!(output.contains("Content-Length: 5\r\n"));
+    assert_eq!(body(&output), "");
+}
+
+#[test]
+fn http_1_0_closes_after_one_response() {
+    let output = serve(["GET /hello HTTP/1.0\r\n\r\nGET /hello HTTP/1.0\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert!(output.contains("Connection: close\r\n"));
+}
+
+#[test]
+fn chunked_response() {
+    let output = serve(["GET /chunked HTTP/1.1\r\n\r\n"]);
+    assert!(output.contains("Transfer-Encoding: chunked\r\n"));
+    assert_eq!(
+        body(&output),
+        "5\r\ntick\n\r\n5\r\ntick\n\r\n5\r\ntick\n\r\n0\r\n\r\n"
+    );
+}
+
+#[test]
+fn chunked_response_to_http_1_0_is_unframed() {
+    let output = serve(["GET /chunked HTTP/1.0\r\n\r\n"]);
+    assert!(!output.contains("Transfer-Encoding"));
+    assert!(output.contains("Connection: close\r\n"));
+    assert_eq!(body(&output), "tick\ntick\ntick\n");
+}
+
+#[test]
+fn accept_encoding_negotiation() {
+    let get = |accept_encoding: &str| {
+        let request = format!("GET /gzip HTTP/1.1\r\n{accept_encoding}\r\n");
+        let output = serve([request]);
+        assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+        output.rsplit("\r\n\r\n").next().unwrap().to_owned()
+    };
+    for accepted in [
+        "Accept-Encoding: gzip, deflate, br\r\n",
+        "Accept-Encoding: GZIP;q=0.5\r\n",
+        "Accept-Encoding: *\r\n",
+    ] {
+        assert_eq!(get(accepted), "gzip", "{accepted}");
+    }
+    for refused in [
+        "",
+        "Accept-Encoding: identity\r\n",
+        "Accept-Encoding: gzip;q=0, br\r\n",
+        "Accept-Encoding: *, gzip;q=0\r\n",
+        "Accept-Encoding: x-gzip\r\n",
+    ] {
+        assert_eq!(get(refused), "identity", "{refused:?}");
+    }
+}
+
+#[test]
+fn unauthorized_asks_for_a_login() {
+    let server = HttpServer::new().with_auth_challenge("Basic realm=\"Test\"");
+    let output = serve_with(&server, ["GET /private HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 401 Unauthorized"]);
+    assert!(output.contains("WWW-Authenticate: Basic realm=\"Test\"\r\n"));
+}
+
+#[test]
+fn cross_origin_post_is_forbidden() {
+    let output = serve([
+        "POST /echo HTTP/1.1\r\nHost: device\r\nOrigin: http://evil.example\r\nContent-Length: 2\r\n\r\nhi",
+    ]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 403 Forbidden"]);
+}
+
+#[test]
+fn cross_origin_query_token_does_not_cover_basic_login() {
+    // The browser adds the admin's remembered login, the token would never be checked
+    let output = serve([
+        "POST /echo?access_token=good HTTP/1.1\r\nHost: device\r\nOrigin: http://evil.example\r\nAuthorization: Basic YWRtaW46c2VjcmV0\r\nContent-Length: 2\r\n\r\nhi",
+    ]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 403 Forbidden"]);
+}
+
+#[test]
+fn cross_origin_made_up_token_is_forbidden() {
+    let output = serve([
+        "POST /echo HTTP/1.1\r\nHost: device\r\nOrigin: http://evil.example\r\nAuthorization: Bearer made-up\r\nContent-Length: 2\r\n\r\nhi",
+        "POST /echo?access_token=made-up HTTP/1.1\r\nHost: device\r\nOrigin: http://evil.example\r\nContent-Length: 2\r\n\r\nhi",
+    ]);
+    assert_eq!(
+        status_lines(&output),
+        ["HTTP/1.1 403 Forbidden", "HTTP/1.1 403 Forbidden"]
+    );
+}
+
+#[test]
+fn cross_origin_valid_token_is_allowed() {
+    let output = serve([
+        "POST /echo?access_token=good HTTP/1.1\r\nHost: device\r\nOrigin: http://panel.example\r\nContent-Length: 2\r\n\r\nhi",
+        "POST /echo HTTP/1.1\r\nHost: device\r\nOrigin: http://panel.example\r\nAuthorization: Bearer good\r\nContent-Length: 2\r\n\r\nhi",
+    ]);
+    assert_eq!(
+        status_lines(&output),
+        ["HTTP/1.1 200 OK", "HTTP/1.1 200 OK"]
+    );
+}
+
+#[test]
+fn same_origin_post_is_allowed() {
+    let output = serve([
+        "POST /echo HTTP/1.1\r\nHost: device\r\nOrigin: http://device\r\nContent-Length: 2\r\n\r\nhi",
+    ]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+}
+
+#[test]
+fn cors_preflight() {
+    let server = HttpServer::new().with_cors(CORS);
+    let output = serve_with(
+        &server,
+        ["OPTIONS /echo HTTP/1.1\r\nHost: device\r\nOrigin: http://panel.local\r\nAccess-Control-Request-Method: POST\r\n\r\n"],
+    );
+    assert_eq!(status_lines(&output), ["HTTP/1.1 204 No Content"]);
+    assert!(output.contains("Access-Control-Allow-Origin: http://panel.local\r\n"));
+    assert!(output.contains("Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n"));
+}
+
+#[test]
+fn cors_listed_origin_can_post() {
+    let server = HttpServer::new().with_cors(CORS);
+    let output = serve_with(
+        &server,
+        ["POST /echo HTTP/1.1\r\nHost: device\r\nOrigin: http://panel.local\r\nContent-Length: 2\r\n\r\nhi"],
+    );
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert!(output.contains("Access-Control-Allow-Origin: http://panel.local\r\n"));
+}
+
+/// A masked client text frame, clients have to mask everything they send
+fn client_text_frame(text: &str) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x81, 0x80 | text.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(text.bytes().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    frame
+}
+
+const UPGRADE: &str = "GET /ws HTTP/1.1\r\nHost: device\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+#[test]
+fn websocket_upgrade_and_echo() {
+    let mut first_read = UPGRADE.as_bytes().to_vec();
+    // Frames sent straight after the handshake are in the same read
+    first_read.extend(client_text_frame("ping"));
+    let output = serve_raw(&HttpServer::new(), [first_read, client_text_frame("again")]);
+    let (head, frames) =
+        output.split_at(output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4);
+    let head = core::str::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    // Accept key for the example in RFC 6455
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert_eq!(frames, b"\x81\x04ping\x81\x05again");
+}
+
+#[test]
+fn websocket_frame_split_across_reads() {
+    let frame = client_text_frame("split");
+    let output = serve_raw(
+        &HttpServer::new(),
+        [UPGRADE.as_bytes(), &frame[..3], &frame[3..]],
+    );
+    assert!(output.ends_with(b"\x81\x05split"));
+}
+
+#[test]
+fn websocket_unmasked_frame_is_a_protocol_error() {
+    let output = serve_raw(&HttpServer::new(), [UPGRADE.as_bytes(), b"\x81\x02hi"]);
+    // Close frame with status 1002
+    assert!(output.ends_with(b"\x88\x02\x03\xea"));
+}
+
+#[test]
+fn websocket_upgrade_for_other_paths_is_a_normal_request() {
+    let output = serve([UPGRADE.replace("/ws", "/hello")]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+}
+
+/// A server logging to its own leaked log, tests run in parallel so they can't share one
+fn logged_server() -> (HttpServer, &'static AccessLog) {
+    let access_log: &'static AccessLog = Box::leak(Box::new(AccessLog::new()));
+    (HttpServer::new().with_access_log(access_log), access_log)
+}
+
+fn log_json(access_log: &AccessLog, limit: usize, buffer_len: usize) -> String {
+    let mut buffer = vec![0; buffer_len];
+    String::from_utf8(access_log.write_json(limit, &mut buffer).unwrap().to_vec()).unwrap()
+}
+
+#[test]
+fn access_log_records_requests() {
+    let (server, access_log) = logged_server();
+    let mut stream = MockStream::new([
+        "GET /hello?token=secret HTTP/1.1\r\nHost: device\r\n\r\nGET /missing HTTP/1.1\r\nHost: device\r\n\r\n",
+    ]);
+    let remote = "192.168.4.2:50123".parse().unwrap();
+    let mut buffers = Box::new(ConnectionBuffers::new());
+    block_on(server.serve_connection(&mut stream, Some(remote), &TestHandler, &mut buffers));
+
+    let json = log_json(access_log, ACCESS_LOG_LEN, 2_048);
+    assert!(json.starts_with(
+        r#"{"counters":{"requests":2,"client_errors":1,"server_errors":0,"parse_failures":0},"entries":[{"id":2,"#
+    ));
+    assert!(json.contains(
+        r#""remote":"192.168.4.2:50123","method":"GET","path":"/missing","status":404,"#
+    ));
+    assert!(json.contains(r#""path":"/hello","status":200,"#));
+    assert!(!json.contains("secret"));
+    assert!(json.ends_with("}]}"));
+}
+
+#[test]
+fn access_log_counts_parse_failures() {
+    let (server, access_log) = logged_server();
+    serve_with(&server, ["GARBAGE\r\n\r\n"]);
+    let counters = access_log.counters();
+    assert_eq!(
+        (
+            counters.requests,
+            counters.client_errors,
+            counters.parse_failures
+        ),
+        (1, 1, 1)
+    );
+    assert!(log_json(access_log, ACCESS_LOG_LEN, 512)
+        .contains(r#""remote":null,"method":null,"path":"","status":400,"#));
+}
+
+#[test]
+fn access_log_drops_the_oldest_and_keeps_the_newest_that_fit() {
+    let (server, access_log) = logged_server();
+    let requests = "GET /hello HTTP/1.1\r\nHost: device\r\n\r\n".repeat(ACCESS_LOG_LEN + 4);
+    serve_with(&server, [requests]);
+    assert_eq!(access_log.counters().requests, ACCESS_LOG_LEN as u32 + 4);
+
+    let mut ids = Vec::new();
+    access_log.for_each_newest_first(|entry| ids.push(entry.id));
+    assert_eq!(ids.len(), ACCESS_LOG_LEN);
+    assert_eq!(ids[0], ACCESS_LOG_LEN as u32 + 4);
+
+    let limited = log_json(access_log, 2, 2_048);
+    assert_eq!(limited.matches(r#""id":"#).count(), 2);
+    // Only whole entries go in, starting from the newest
+    let small = log_json(access_log, ACCESS_LOG_LEN, 300);
+    assert!(small.contains(&format!(r#""id":{},"#, ACCESS_LOG_LEN + 4)));
+    assert!(small.ends_with("}]}"));
+}
+
+/// Short enough that slow clients can be played in tests
+const TEST_TIMEOUTS: Timeouts = Timeouts {
+    keep_alive: Duration::from_millis(100),
+    header: Duration::from_millis(100),
+    body: Duration::from_millis(100),
+    write: Duration::from_millis(100),
+};
+
+fn serve_stream(server: &HttpServer, stream: &mut MockStream, remote: Option<&str>) -> String {
+    let mut buffers = Box::new(ConnectionBuffers::new());
+    let remote = remote.map(|remote| remote.parse().unwrap());
+    block_on(server.serve_connection(stream, remote, &TestHandler, &mut buffers));
+    String::from_utf8(stream.written.clone()).unwrap()
+}
+
+#[test]
+fn slow_headers_are_cut_off() {
+    let server = HttpServer::new().with_timeouts(TEST_TIMEOUTS);
+    let request = "GET /hello HTTP/1.1\r\nHost: device\r\n\r\n";
+    let mut stream = MockStream::new(request.as_bytes().chunks(1));
+    stream.read_delay = Duration::from_millis(20);
+    let started = Instant::now();
+    assert_eq!(serve_stream(&server, &mut stream, None), "");
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn slow_body_is_cut_off() {
+    let server = HttpServer::new().with_timeouts(TEST_TIMEOUTS);
+    let mut reads = vec!["POST /echo HTTP/1.1\r\nHost: device\r\nContent-Length: 10\r\n\r\n"];
+    reads.extend(["x"; 10]);
+    let mut stream = MockStream::new(reads);
+    stream.read_delay = Duration::from_millis(30);
+    assert_eq!(serve_stream(&server, &mut stream, None), "");
+}
+
+#[test]
+fn each_keep_alive_request_gets_its_own_header_timeout() {
+    let server = HttpServer::new().with_timeouts(TEST_TIMEOUTS);
+    let request = "GET /hello HTTP/1.1\r\nHost: device\r\n\r\n";
+    let mut stream = MockStream::new([request; 3]);
+    stream.read_delay = Duration::from_millis(60);
+    let output = serve_stream(&server, &mut stream, None);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"; 3]);
+}
+
+#[test]
+fn stalled_writes_time_out() {
+    let server = HttpServer::new().with_timeouts(TEST_TIMEOUTS);
+    let mut stream = MockStream::new(["GET /hello HTTP/1.1\r\nHost: device\r\n\r\n"]);
+    stream.stall_writes = true;
+    // Returning at all is the test, the write would otherwise wait forever
+    assert_eq!(serve_stream(&server, &mut stream, None), "");
+}
+
+#[test]
+fn repeated_bad_requests_get_the_client_banned() {
+    let server = HttpServer::new();
+    for _ in 0..3 {
+        let output = serve_stream(
+            &server,
+            &mut MockStream::new(["GARBAGE\r\n\r\n"]),
+            Some("192.168.4.2:50000"),
+        );
+        assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+    }
+
+    let request = "GET /hello HTTP/1.1\r\nHost: device\r\n\r\n";
+    let banned = serve_stream(
+        &server,
+        &mut MockStream::new([request]),
+        Some("192.168.4.2:50001"),
+    );
+    assert_eq!(banned, "");
+    let other = serve_stream(
+        &server,
+        &mut MockStream::new([request]),
+        Some("192.168.4.3:50000"),
+    );
+    assert_eq!(status_lines(&other), ["HTTP/1.1 200 OK"]);
+}
+
+#[test]
+fn repeated_failed_logins_get_the_client_banned() {
+    let server = HttpServer::new().with_auth_challenge("Basic realm=\"Test\"");
+    let login =
+        "GET /login HTTP/1.1\r\nHost: device\r\nAuthorization: Basic YWRtaW46Z3Vlc3M=\r\n\r\n";
+    let first = serve_stream(
+        &server,
+        &mut MockStream::new([login]),
+        Some("192.168.4.2:50000"),
+    );
+    assert_eq!(status_lines(&first), ["HTTP/1.1 401 Unauthorized"]);
+    assert!(first.contains("WWW-Authenticate: Basic realm=\"Test\"\r\n"));
+
+    // The strike that bans also ends the connection it came in on
+    let guesses = serve_stream(
+        &server,
+        &mut MockStream::new([login, login, login]),
+        Some("192.168.4.2:50001"),
+    );
+    assert_eq!(
+        status_lines(&guesses),
+        ["HTTP/1.1 401 Unauthorized", "HTTP/1.1 401 Unauthorized"]
+    );
+    let banned = serve_stream(
+        &server,
+        &mut MockStream::new(["GET /hello HTTP/1.1\r\nHost: device\r\n\r\n"]),
+        Some("192.168.4.2:50002"),
+    );
+    assert_eq!(banned, "");
+}
+
+#[test]
+fn rate_limiter_allows_a_burst_then_one_per_interval() {
+    let limiter = RateLimiter::<4>::new(2, Duration::from_millis(50));
+    let a = Some("192.168.4.2".parse().unwrap());
+    let b = Some("192.168.4.3".parse().unwrap());
+    assert!(limiter.check(a));
+    assert!(limiter.check(a));
+    assert!(!limiter.check(a));
+    assert!(limiter.check(b));
+
+    std::thread::sleep(std::time::Duration::from_millis(60));
+    assert!(limiter.check(a));
+    assert!(!limiter.check(a));
+}
+
+#[test]
+fn rate_limiter_charges_several_at_once() {
+    let limiter = RateLimiter::<4>::new(3, Duration::from_millis(50));
+    let a = Some("192.168.4.2".parse().unwrap());
+    assert!(!limiter.check_n(a, 4));
+    assert!(limiter.check_n(a, 2));
+    // Only one left, asking for two fails without using it up
+    assert!(!limiter.check_n(a, 2));
+    assert!(limiter.check(a));
+    assert!(!limiter.check(a));
+    assert!(limiter.check_n(a, 0));
+
+    std::thread::sleep(std::time::Duration::from_millis(110));
+    assert!(limiter.check_n(a, 2));
+    assert!(!limiter.check(a));
+}
+
+// Inputs worth keeping from the fuzz targets in fuzz/, cargo fuzz tmin makes crashes small
+// enough to paste in here
+
+#[test]
+fn blank_line_inside_the_body_stays_in_the_body() {
+    let output = serve(["POST /echo HTTP/1.1\r\nContent-Length: 8\r\n\r\na\r\n\r\nbcd"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+    assert_eq!(body(&output), "a\r\n\r\nbcd");
+}
+
+#[test]
+fn unknown_method_reaches_the_handler() {
+    let output = serve(["BREW /hello HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 404 Not Found"]);
+}
+
+#[test]
+fn missing_path_is_a_bad_request() {
+    let output = serve(["GET HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+}
+
+#[test]
+fn header_without_a_colon_is_a_bad_request() {
+    let output = serve(["GET /hello HTTP/1.1\r\nHost device\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+}
+
+#[test]
+fn non_utf8_header_values_are_ignored() {
+    let output = serve([b"GET /hello HTTP/1.1\r\nOrigin: \xff\xfe\r\n\r\n".as_slice()]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+}
+
+#[test]
+fn truncated_percent_escapes_in_the_query() {
+    let output = serve(["GET /hello?a=%&b=%4&c=%zz&%=1 HTTP/1.1\r\n\r\n"]);
+    assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+}
+
+#[test]
+fn json_with_escapes_and_wrong_types() {
+    for body in [
+        "{\"name\":\"\\u00e9\\n\"}",
+        "{\"name\":\"\\ud800\"}",
+        "{\"name\":[1,2]}",
+        "{\"name\":\"a\",\"name\":\"b\"}",
+        "[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[",
+    ] {
+        let request = format!(
+            "POST /greet HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let output = serve([request]);
+        assert_eq!(status_lines(&output).len(), 1, "{}", body);
+    }
+}
+
+#[test]
+fn websocket_frame_claiming_to_be_huge() {
+    let mut frame = vec![0x81, 0xFF];
+    frame.extend_from_slice(&u64::MAX.to_be_bytes());
+    frame.extend_from_slice(&[1, 2, 3, 4]);
+    let output = serve_raw(&HttpServer::new(), [UPGRADE.as_bytes(), &frame]);
+    // Close frame with status 1009, message too big
+    assert!(output.ends_with(b"\x88\x02\x03\xf1"));
+}
+
+#[test]
+fn websocket_oversized_ping_is_a_protocol_error() {
+    // Control frames can't have more than 125 bytes of payload
+    let mut frame = vec![0x89, 0x80 | 126, 0, 200, 0, 0, 0, 0];
+    frame.extend_from_slice(&[0; 200]);
+    let output = serve_raw(&HttpServer::new(), [UPGRADE.as_bytes(), &frame]);
+    assert!(output.ends_with(b"\x88\x02\x03\xea"));
+}
@@ -0,0 +1,229 @@
+//! The template engine on its own, without a server around it.
+//! Run with `cargo host-test` from the repo root
+use http_server::io::template::{self, TemplateBody, TemplateContext, Value};
+use http_server::ChunkedBody;
+
+fn render(template: &str, context: &dyn TemplateContext) -> Result<String, &'static str> {
+    let mut buffer = vec![0u8; 4096];
+    template::render_to_str(template, context, &mut buffer).map(str::to_owned)
+}
+
+/// Everything a `TemplateBody` sends when the server's buffer is `chunk_len` long
+fn render_chunked(template: &str, context: &dyn TemplateContext, chunk_len: usize) -> String {
+    let mut body = TemplateBody::new(template, context);
+    let mut buffer = vec![0u8; chunk_len];
+    let mut output = Vec::new();
+    loop {
+        let len = body.next_chunk(&mut buffer);
+        if len == 0 {
+            break;
+        }
+        output.extend_from_slice(&buffer[..len]);
+    }
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn values_are_escaped() {
+    let context = [("name", Value::Str(r#"<a href="x">Tom & 'Jerry'</a>"#))];
+    assert_eq!(
+        render("<p>{{name}}</p>", &context).unwrap(),
+        "<p>&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;</p>"
+    );
+    assert_eq!(
+        render("{{{name}}}|{{& name}}", &context).unwrap(),
+        r#"<a href="x">Tom & 'Jerry'</a>|<a href="x">Tom & 'Jerry'</a>"#
+    );
+    // Multi-byte characters next to escaped ones stay whole
+    let context = [("name", Value::Str("é<ü>ß"))];
+    assert_eq!(render("{{ name }}", &context).unwrap(), "é&lt;ü&gt;ß");
+}
+
+#[test]
+fn numbers_and_bools() {
+    let context = [
+        ("int", Value::Int(-42)),
+        ("uint", Value::UInt(u64::MAX)),
+        ("on", Value::Bool(true)),
+    ];
+    assert_eq!(
+        render("{{int}} {{uint}} {{on}}", &context).unwrap(),
+        "-42 18446744073709551615 true"
+    );
+}
+
+#[test]
+fn if_and_else() {
+    let template = "{{#if value}}yes{{else}}no{{/if}}";
+    for (value, expected) in [
+        (Value::Bool(true), "yes"),
+        (Value::Bool(false), "no"),
+        (Value::Int(0), "no"),
+        (Value::UInt(3), "yes"),
+        (Value::Str(""), "no"),
+        (Value::Str("x"), "yes"),
+        (Value::List(&[] as &[&str; 0]), "no"),
+        (Value::List(&["a"]), "yes"),
+    ] {
+        assert_eq!(render(template, &[("value", value)]).unwrap(), expected);
+    }
+    assert_eq!(
+        render(
+            "[{{#if value}}yes{{/if}}]",
+            &[("value", Value::Bool(false))]
+        )
+        .unwrap(),
+        "[]"
+    );
+}
+
+#[test]
+fn nested_sections() {
+    let template = "{{#if outer}}\
+        {{#if inner}}both{{else}}outer only{{/if}}\
+        {{else}}\
+        {{#if inner}}inner only{{else}}neither{{/if}}\
+        {{/if}}";
+    for (outer, inner, expected) in [
+        (true, true, "both"),
+        (true, false, "outer only"),
+        (false, true, "inner only"),
+        (false, false, "neither"),
+    ] {
+        let context = [("outer", Value::Bool(outer)), ("inner", Value::Bool(inner))];
+        assert_eq!(render(template, &context).unwrap(), expected);
+    }
+}
+
+#[test]
+fn each_looks_up_the_item_then_the_outer_context() {
+    let rows = [
+        [("name", Value::Str("kitchen")), ("on", Value::Bool(true))],
+        [("name", Value::Str("hall")), ("on", Value::Bool(false))],
+    ];
+    let context = [
+        ("rows", Value::List(&rows)),
+        ("unit", Value::Str("lamp")),
+        ("name", Value::Str("outer")),
+    ];
+    assert_eq!(
+        render(
+            "{{#each rows}}<li>{{name}} {{unit}}{{#if on}} on{{/if}}</li>{{/each}}",
+            &context
+        )
+        .unwrap(),
+        "<li>kitchen lamp on</li><li>hall lamp</li>"
+    );
+
+    let tags = ["a&b", "c"];
+    let context = [("tags", Value::List(&tags)), ("show", Value::Bool(true))];
+    assert_eq!(
+        render(
+            "{{#if show}}{{#each tags}}[{{.}}]{{/each}}{{/if}}",
+            &context
+        )
+        .unwrap(),
+        "[a&amp;b][c]"
+    );
+}
+
+#[test]
+fn unknown_names_are_errors() {
+    let context = [("known", Value::Bool(true))];
+    assert_eq!(
+        render("{{missing}}", &context),
+        Err("Unknown template value")
+    );
+    assert_eq!(
+        render("{{#if missing}}x{{/if}}", &context),
+        Err("Unknown template value")
+    );
+    assert_eq!(
+        render("{{#if known}}{{{missing}}}{{/if}}", &context),
+        Err("Unknown template value")
+    );
+}
+
+#[test]
+fn wrong_value_kinds_are_errors() {
+    let context = [("list", Value::List(&["a"])), ("text", Value::Str("a"))];
+    assert_eq!(
+        render("{{list}}", &context),
+        Err("Lists can only be used with {{#each}}")
+    );
+    assert_eq!(
+        render("{{#each text}}x{{/each}}", &context),
+        Err("{{#each}} needs a list")
+    );
+    assert_eq!(
+        render("{{#each list}}x{{else}}y{{/each}}", &context),
+        Err("{{else}} only works in {{#if}}")
+    );
+}
+
+#[test]
+fn unbalanced_and_unterminated_tags_are_errors() {
+    let context = [("a", Value::Bool(true)), ("list", Value::List(&["x"]))];
+    for (template, expected) in [
+        ("{{a", "Template tag is never closed"),
+        ("{{{a}}", "Template tag is never closed"),
+        ("{{#if a}}x{{/if", "Template tag is never closed"),
+        ("{{#if a}}x", "Template section is never closed"),
+        (
+            "{{#if a}}{{#if a}}x{{/if}}",
+            "Template section is never closed",
+        ),
+        ("x{{/if}}", "Template section ends without starting"),
+        ("{{else}}", "Template section ends without starting"),
+        (
+            "{{#if a}}{{#each list}}x{{/if}}{{/each}}",
+            "Template sections are closed in the wrong order",
+        ),
+        (
+            "{{#if a}}x{{/each}}",
+            "Template sections are closed in the wrong order",
+        ),
+    ] {
+        assert_eq!(render(template, &context), Err(expected), "{template}");
+    }
+}
+
+#[test]
+fn output_longer_than_the_buffer_is_an_error() {
+    let context = [("name", Value::Str("0123456789"))];
+    let mut buffer = [0u8; 8];
+    assert_eq!(
+        template::render_to_str("{{name}}", &context, &mut buffer),
+        Err("Template output doesn't fit")
+    );
+}
+
+#[test]
+fn chunked_output_matches_the_whole_render() {
+    let long = "<tag attr=\"&\">é</tag> ".repeat(20);
+    let rows = [
+        [("name", Value::Str("first & one"))],
+        [("name", Value::Str("second <two>"))],
+        [("name", Value::Str(long.as_str()))],
+    ];
+    let context = [
+        ("long", Value::Str(long.as_str())),
+        ("rows", Value::List(&rows)),
+        ("count", Value::UInt(1234567890)),
+        ("on", Value::Bool(true)),
+    ];
+    let template = "<h1>{{long}}</h1>{{{long}}}\
+        <ul>{{#each rows}}<li>{{name}} of {{count}}</li>{{/each}}</ul>\
+        {{#if on}}{{long}}{{else}}off{{/if}}";
+    let whole = render(template, &context).unwrap();
+    assert!(whole.len() > 1000);
+
+    // Short buffers split single placeholders, entities and characters across many chunks
+    for chunk_len in (1..=17).chain([64, 1000, whole.len(), whole.len() + 1]) {
+        assert_eq!(
+            render_chunked(template, &context, chunk_len),
+            whole,
+            "chunk_len {chunk_len}"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* Ends at 1M, the settings sector save.rs writes at 0x100000 comes right after. */
+    /* An image too big for this fails to link instead of overwriting the settings  */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 1024K - 0x100
 
     /* Pick one of the two options for RAM layout     */
 
@@ -0,0 +1,130 @@
+//! Request and response bodies for the versioned JSON API under `/api/v1`
+use crate::auth::Access;
+use crate::dhcp_server::MAX_LEASES;
+use crate::save::MAX_SAVED_NETWORKS;
+use crate::wifi::MAX_SCAN_RESULTS;
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+/// `POST /api/v1/command`
+#[derive(Deserialize)]
+pub struct CommandRequest {
+    pub command: u8,
+}
+
+#[derive(Serialize)]
+pub struct CommandResponse {
+    pub sent: u8,
+}
+
+/// `GET /api/v1/status`
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub light_on: bool,
+    pub access_point_mode: bool,
+    /// The joined network, empty while joining or running the setup access point
+    pub wifi_ssid: String<32>,
+    pub uptime_secs: u64,
+    /// False until the admin password is set, the web app sends you to `/setup` then
+    pub admin_password_set: bool,
+    /// Clients of the setup access point, empty when joined to a network. Left out unless
+    /// the caller is an admin, since it says which devices are around
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dhcp_leases: Option<Vec<DhcpLease, MAX_LEASES>>,
+}
+
+#[derive(Serialize)]
+pub struct DhcpLease {
+    pub mac: String<17>,
+    pub ip: String<15>,
+    pub expires_in_secs: u64,
+}
+
+/// `GET /api/v1/config`, the password is never sent back
+#[derive(Serialize)]
+pub struct ConfigResponse<'a> {
+    pub wifi_ssid: &'a str,
+    /// True while joined to another network, which is kept until it goes away or the device
+    /// restarts. From the setup access point a saved network is tried straight away
+    pub restart_required: bool,
+}
+
+/// `POST /api/v1/config`
+#[derive(Deserialize)]
+pub struct ConfigRequest {
+    pub wifi_ssid: String<32>,
+    pub wifi_password: String<32>,
+}
+
+/// `GET /api/v1/wifi/scan`, strongest first
+#[derive(Serialize)]
+pub struct WifiScanResponse {
+    pub networks: Vec<WifiNetwork, MAX_SCAN_RESULTS>,
+}
+
+#[derive(Serialize)]
+pub struct WifiNetwork {
+    pub ssid: String<32>,
+    /// dBm
+    pub rssi: i16,
+    pub channel: u8,
+    pub security: WifiSecurity,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiSecurity {
+    Open,
+    /// WEP, WPA or anything newer, the scan doesn't say which
+    Secured,
+}
+
+/// `GET /api/v1/wifi/networks`, and the reply to changing the list. Passwords are never sent back
+#[derive(Serialize)]
+pub struct SavedNetworksResponse<'a> {
+    pub networks: Vec<SavedNetworkInfo<'a>, MAX_SAVED_NETWORKS>,
+    /// The network joined most recently, None until one has been
+    pub last_connected: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+pub struct SavedNetworkInfo<'a> {
+    pub ssid: &'a str,
+    /// 0 is tried first
+    pub priority: usize,
+}
+
+/// `POST /api/v1/wifi/networks`, adds a network or changes its password. Remove one with
+/// `DELETE /api/v1/wifi/networks?ssid=`
+#[derive(Deserialize)]
+pub struct AddNetworkRequest {
+    pub ssid: String<32>,
+    pub password: String<32>,
+    /// Where it goes in the list, new networks go last and changed ones stay put without it
+    pub priority: Option<usize>,
+}
+
+/// `PUT /api/v1/wifi/networks/order`, the listed SSIDs go first in this order
+#[derive(Deserialize)]
+pub struct ReorderNetworksRequest {
+    pub ssids: Vec<String<32>, MAX_SAVED_NETWORKS>,
+}
+
+/// `POST /api/v1/auth/password`, sets the admin password. Open on first boot, admin only after
+#[derive(Deserialize)]
+pub struct PasswordRequest {
+    pub password: String<64>,
+}
+
+/// `POST /api/v1/auth/token`
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    pub access: Access,
+}
+
+/// Send the token as `Authorization: Bearer <token>`, or as `?access_token=` where headers can't be set
+#[derive(Serialize)]
+pub struct TokenResponse<'a> {
+    pub token: &'a str,
+    pub access: Access,
+}
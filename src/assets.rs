@@ -0,0 +1,63 @@
+//! Every file under `web_app/`, bundled by `build.rs`. Files are stored as is and, when it
+//! makes them smaller, gzipped too. Clients that accept gzip get that copy with
+//! `Content-Encoding: gzip`, everyone else the plain one
+use http_server::{Response, StatusCode, WebRequest};
+
+pub struct Asset {
+    /// Route the file is served at, like `/` for `index.html` or `/wifi` for `wifi.html`
+    pub path: &'static str,
+    pub content_type: &'static str,
+    /// Quoted hash of the file, changes whenever the file does
+    pub etag: &'static str,
+    pub cache_control: &'static str,
+    pub body: &'static [u8],
+    pub gzipped: Option<Gzipped>,
+}
+
+pub struct Gzipped {
+    /// The file's ETag with `-gzip` added inside the quotes
+    pub etag: &'static str,
+    pub body: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+impl Asset {
+    pub fn find(path: &str) -> Option<&'static Asset> {
+        ASSETS.iter().find(|asset| asset.path == path)
+    }
+
+    /// The file, or a `304` when the browser's cached copy is still current
+    pub fn response<'a>(&self, request: &WebRequest<'_, '_>) -> Response<'a> {
+        let (etag, body, gzipped) = match &self.gzipped {
+            Some(gzipped) if request.accepts_encoding("gzip") => (gzipped.etag, gzipped.body, true),
+            _ => (self.etag, self.body, false),
+        };
+        let mut response = if matches(request.header("If-None-Match"), etag) {
+            Response::new_empty(StatusCode::NotModified)
+        } else {
+            Response::new_with_content_type(StatusCode::Ok, self.content_type, body)
+        };
+        response.add_header("ETag", etag);
+        response.add_header("Cache-Control", self.cache_control);
+        if gzipped {
+            response.add_header("Content-Encoding", "gzip");
+        }
+        // Caches have to keep the two copies apart
+        if self.gzipped.is_some() {
+            response.add_header("Vary", "Accept-Encoding");
+        }
+        response
+    }
+}
+
+/// `If-None-Match` is a list of ETags or `*`. Weak ETags count since the body is
+/// the same either way
+fn matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let Some(if_none_match) = if_none_match else {
+        return false;
+    };
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
@@ -0,0 +1,241 @@
+//! Admin password and bearer tokens for the HTTP endpoints. The password is only ever
+//! stored as a salted PBKDF2-HMAC-SHA256 hash, tokens live in RAM and are gone after a reboot
+use base64::{engine::general_purpose::STANDARD, Engine};
+use core::cell::{Cell, RefCell};
+use defmt::*;
+use embassy_rp::clocks::RoscRng;
+use heapless::{String, Vec};
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use http_server::{WebRequest, WebRequestHandlerError};
+
+/// Most tokens that can be handed out at once, the oldest is dropped to make room
+const MAX_TOKENS: usize = 4;
+/// Every guess at the password costs this many HMACs, for someone with a copy of the flash
+/// as much as for the device. Far below what a PC would use since the RP2040 takes a
+/// noticeable part of a second for it, which is why a login that worked is remembered
+const PBKDF2_ROUNDS: u32 = 4096;
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// Browsers use the realm in the login prompt
+pub const REALM: &str = "Basic realm=\"Picosapien\"";
+
+/// Protection levels for routes, each level includes the ones below it
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, defmt::Format, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Public,
+    /// Can drive the robot
+    Operator,
+    /// Can change the configuration
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, defmt::Format)]
+pub struct PasswordHash {
+    pub salt: [u8; 16],
+    pub hash: [u8; 32],
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> Self {
+        let mut salt = [0u8; 16];
+        RoscRng.fill_bytes(&mut salt);
+        Self {
+            salt,
+            hash: hash_password(&salt, password),
+        }
+    }
+
+    pub fn matches(&self, password: &str) -> bool {
+        constant_time_eq(&hash_password(&self.salt, password), &self.hash)
+    }
+}
+
+fn hash_password(salt: &[u8; 16], password: &str) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut hash);
+    hash
+}
+
+/// Doesn't return early so the time taken doesn't leak how much of a secret matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+struct Token {
+    value: String<32>,
+    access: Access,
+}
+
+pub struct Auth {
+    password: Cell<Option<PasswordHash>>,
+    tokens: RefCell<Vec<Token, MAX_TOKENS>>,
+    /// Random for every boot, mixed into `verified_login`
+    login_key: [u8; 16],
+    /// Quick hash of the last Basic credentials that matched, so the browser sending them
+    /// with every request doesn't run the slow password hash every time
+    verified_login: Cell<Option<[u8; 32]>>,
+}
+
+impl Auth {
+    pub fn new(password: Option<PasswordHash>) -> Self {
+        let mut login_key = [0u8; 16];
+        RoscRng.fill_bytes(&mut login_key);
+        Self {
+            password: Cell::new(password),
+            tokens: RefCell::new(Vec::new()),
+            login_key,
+            verified_login: Cell::new(None),
+        }
+    }
+
+    /// False until the admin password is set on first boot
+    pub fn password_set(&self) -> bool {
+        self.password.get().is_some()
+    }
+
+    /// Replaces the admin password and revokes every token that was handed out with the old one
+    pub fn set_password(&self, password: PasswordHash) {
+        self.password.set(Some(password));
+        self.tokens.borrow_mut().clear();
+        self.verified_login.set(None);
+    }
+
+    /// Makes a random token for the `Authorization: Bearer` header or `access_token` query parameter
+    pub fn issue_token(&self, access: Access) -> String<32> {
+        let mut random = [0u8; 16];
+        RoscRng.fill_bytes(&mut random);
+        let mut value = String::new();
+        for byte in random {
+            let _ = value.push(hex_digit(byte >> 4));
+            let _ = value.push(hex_digit(byte & 0x0F));
+        }
+
+        let mut tokens = self.tokens.borrow_mut();
+        if tokens.is_full() {
+            tokens.remove(0);
+        }
+        let _ = tokens.push(Token {
+            value: value.clone(),
+            access,
+        });
+        value
+    }
+
+    /// The highest access level the request's credentials give
+    pub fn access(&self, request: &WebRequest<'_, '_>) -> Access {
+        let basic = request
+            .header("Authorization")
+            .and_then(|authorization| strip_prefix_ignore_case(authorization.trim(), "Basic "));
+        match basic {
+            Some(credentials) => self.basic_access(credentials.trim()),
+            None => self.token_access_of(request),
+        }
+    }
+
+    /// Access from a token alone. Browsers never send one on their own the way they send a
+    /// remembered Basic login, so the query only counts without an `Authorization` header
+    pub fn token_access_of(&self, request: &WebRequest<'_, '_>) -> Access {
+        if let Some(authorization) = request.header("Authorization") {
+            return match strip_prefix_ignore_case(authorization.trim(), "Bearer ") {
+                Some(token) => self.token_access(token.trim()),
+                None => Access::Public,
+            };
+        }
+        // EventSource and WebSocket in the browser can't set headers
+        if let Some(token) = request.query_param("access_token") {
+            let mut buffer = [0u8; 32];
+            if let Some(token) = token.decode_into(&mut buffer) {
+                return self.token_access(token);
+            }
+        }
+        Access::Public
+    }
+
+    pub fn authorize(
+        &self,
+        request: &WebRequest<'_, '_>,
+        required: Access,
+    ) -> Result<(), WebRequestHandlerError> {
+        if required == Access::Public {
+            return Ok(());
+        }
+        match self.access(request) {
+            access if access >= required => Ok(()),
+            // Credentials that were sent but didn't work count towards banning the client
+            Access::Public
+                if request.header("Authorization").is_some()
+                    || request.query_param("access_token").is_some() =>
+            {
+                Err(WebRequestHandlerError::BadCredentials)
+            }
+            Access::Public => Err(WebRequestHandlerError::Unauthorized),
+            _ => Err(WebRequestHandlerError::Forbidden(
+                "Credentials don't allow this",
+            )),
+        }
+    }
+
+    /// Basic auth with the user name `admin` and the admin password
+    fn basic_access(&self, credentials: &str) -> Access {
+        let Some(password_hash) = self.password.get() else {
+            return Access::Public;
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(self.login_key);
+        hasher.update(credentials.as_bytes());
+        let login: [u8; 32] = hasher.finalize().into();
+        if self
+            .verified_login
+            .get()
+            .is_some_and(|verified| constant_time_eq(&verified, &login))
+        {
+            return Access::Admin;
+        }
+
+        let mut decoded = [0u8; 96];
+        let Ok(len) = STANDARD.decode_slice(credentials, &mut decoded) else {
+            return Access::Public;
+        };
+        let Ok(decoded) = core::str::from_utf8(&decoded[..len]) else {
+            return Access::Public;
+        };
+        match decoded.split_once(':') {
+            Some(("admin", password)) if password_hash.matches(password) => {
+                self.verified_login.set(Some(login));
+                Access::Admin
+            }
+            _ => {
+                warn!("Failed basic auth attempt");
+                Access::Public
+            }
+        }
+    }
+
+    fn token_access(&self, token: &str) -> Access {
+        self.tokens
+            .borrow()
+            .iter()
+            .find(|issued| constant_time_eq(issued.value.as_bytes(), token.as_bytes()))
+            .map(|issued| issued.access)
+            .unwrap_or(Access::Public)
+    }
+}
+
+fn hex_digit(nibble: u8) -> char {
+    char::from_digit(nibble as u32, 16).unwrap_or('0')
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = value.get(..prefix.len())?;
+    if head.eq_ignore_ascii_case(prefix) {
+        Some(&value[prefix.len()..])
+    } else {
+        None
+    }
+}
@@ -0,0 +1,161 @@
+//! Makes phones and laptops pop up the setup page when they join the setup access point.
+//! Every name resolves to the device, and the connectivity checks operating systems make
+//! after joining a network get redirected to `/wifi` instead of the answer they expect
+use defmt::*;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::Stack;
+
+use crate::wifi_supervisor;
+
+/// The device on the setup access point, it is also the DNS server and gateway there
+pub const AP_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+/// Where connectivity checks are sent, absolute since the probe asked for another host
+pub const PORTAL_URL: &str = "http://192.168.4.1/wifi";
+
+const DNS_PORT: u16 = 53;
+/// Longest query answered, anything bigger isn't a plain lookup
+const DNS_PACKET_LEN: usize = 512;
+const HEADER_LEN: usize = 12;
+/// Short so real names resolve again soon after the device leaves setup mode
+const ANSWER_TTL_SECS: u32 = 60;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_FORMAT_ERROR: u16 = 1;
+const RCODE_NOT_IMPLEMENTED: u16 = 4;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Paths Android, Apple and Windows fetch to check for a captive portal
+pub fn is_connectivity_probe(path: &str) -> bool {
+    matches!(path, "/generate_204" | "/hotspot-detect.html" | "/ncsi.txt")
+}
+
+/// Answers DNS queries while the setup access point is up
+#[embassy_executor::task]
+pub async fn dns_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; DNS_PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; DNS_PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(DNS_PORT) {
+        error!("Captive portal DNS could not bind: {:?}", e);
+        return;
+    }
+    info!("Captive portal DNS answering on port {}", DNS_PORT);
+
+    let mut query = [0; DNS_PACKET_LEN];
+    let mut response = [0; DNS_PACKET_LEN];
+    loop {
+        let (len, from) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("DNS receive error: {:?}", e);
+                continue;
+            }
+        };
+        // Only the access point's clients are meant to be sent to the device
+        if !wifi_supervisor::state().access_point_mode() {
+            continue;
+        }
+        let Some(response_len) = dns_answer(&query[..len], AP_ADDRESS, &mut response) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response[..response_len], from).await {
+            warn!("DNS send error: {:?}", e);
+        }
+    }
+}
+
+/// Builds the reply to `query` in `response`, saying every A record is `address`. Other
+/// record types get an empty answer so clients fall back to A. None when the packet is
+/// too broken or isn't a query
+fn dns_answer(query: &[u8], address: [u8; 4], response: &mut [u8]) -> Option<usize> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    if flags & FLAG_RESPONSE != 0 {
+        return None;
+    }
+    let opcode = (flags >> 11) & 0xF;
+    let question_count = u16::from_be_bytes([query[4], query[5]]);
+
+    // Only standard queries with the one question everyone sends are understood
+    let question = match question_len(query) {
+        Some(len) if opcode == 0 && question_count == 1 => &query[HEADER_LEN..HEADER_LEN + len],
+        _ => &[],
+    };
+    let rcode = match (question.is_empty(), opcode) {
+        (false, _) => 0,
+        (true, 0) => RCODE_FORMAT_ERROR,
+        (true, _) => RCODE_NOT_IMPLEMENTED,
+    };
+    let (record_type, class) = match question {
+        [.., t1, t2, c1, c2] => (
+            u16::from_be_bytes([*t1, *t2]),
+            u16::from_be_bytes([*c1, *c2]),
+        ),
+        _ => (0, 0),
+    };
+    let answered = matches!(record_type, TYPE_A | TYPE_ANY) && class == CLASS_IN;
+
+    let answer_len = if answered { 16 } else { 0 };
+    let len = HEADER_LEN + question.len() + answer_len;
+    if response.len() < len {
+        return None;
+    }
+
+    let response_flags = FLAG_RESPONSE
+        | (opcode << 11)
+        | FLAG_AUTHORITATIVE
+        | (flags & FLAG_RECURSION_DESIRED)
+        | rcode;
+    response[..2].copy_from_slice(&query[..2]);
+    response[2..4].copy_from_slice(&response_flags.to_be_bytes());
+    response[4..6].copy_from_slice(&(!question.is_empty() as u16).to_be_bytes());
+    response[6..8].copy_from_slice(&(answered as u16).to_be_bytes());
+    // No authority or additional records
+    response[8..HEADER_LEN].fill(0);
+    response[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+
+    if answered {
+        let answer = &mut response[HEADER_LEN + question.len()..len];
+        // The name is a pointer back to the one in the question
+        answer[..2].copy_from_slice(&[0xC0, HEADER_LEN as u8]);
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&ANSWER_TTL_SECS.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&address);
+    }
+    Some(len)
+}
+
+/// Length of the first question, its name followed by the type and class. Queries never
+/// compress the name, so a pointer means the packet isn't one
+fn question_len(query: &[u8]) -> Option<usize> {
+    let mut i = HEADER_LEN;
+    loop {
+        let label_len = *query.get(i)? as usize;
+        i += 1;
+        if label_len == 0 {
+            break;
+        }
+        if label_len & 0xC0 != 0 {
+            return None;
+        }
+        i += label_len;
+    }
+    let end = i + 4;
+    (end <= query.len()).then_some(end - HEADER_LEN)
+}
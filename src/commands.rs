@@ -6,4 +6,5 @@ pub enum RobotCommand {
     RightHandPickUp = 0xA4,
     RoseBud = 0xD1,
     StartUpWakeUp = 0xB1,
+    Stop = 0x8E,
 }
@@ -0,0 +1,351 @@
+//! Hands out addresses to phones and laptops that join the setup access point, with the
+//! device as their router and DNS server. Without it clients have to fall back to
+//! link-local addresses, which some never do
+use core::cell::RefCell;
+use defmt::*;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use http_server::io::format_truncated;
+
+use crate::{api, wifi_supervisor};
+
+/// Most clients that can hold a lease at once, the access point doesn't take many more
+pub const MAX_LEASES: usize = 8;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+/// Replies are padded to this, the smallest BOOTP message some clients accept
+const MIN_PACKET_LEN: usize = 300;
+const PACKET_LEN: usize = 576;
+/// op, htype, hlen, hops, xid, secs, flags, four addresses, chaddr, sname and file
+const FIXED_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// How long an offered address is held for the client that was offered it
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+const BOOT_REPLY: u8 = 2;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const DECLINE: u8 = 4;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+#[derive(Clone, Copy)]
+pub struct DhcpConfig {
+    /// The device, given to clients as their router and DNS server
+    pub server: [u8; 4],
+    pub prefix_len: u8,
+    /// First address handed out, the pool counts up from here
+    pub pool_start: [u8; 4],
+    /// Addresses in the pool, only the first `MAX_LEASES` are ever used
+    pub pool_size: u8,
+    pub lease_time: Duration,
+}
+
+#[derive(Clone, Copy)]
+pub struct Lease {
+    pub mac: [u8; 6],
+    pub ip: [u8; 4],
+    pub expires: Instant,
+    /// False while the address has only been offered
+    pub bound: bool,
+}
+
+impl Lease {
+    pub fn to_api(&self) -> api::DhcpLease {
+        let [a, b, c, d] = self.ip;
+        let [m0, m1, m2, m3, m4, m5] = self.mac;
+        api::DhcpLease {
+            mac: format_truncated(format_args!(
+                "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                m0, m1, m2, m3, m4, m5
+            )),
+            ip: format_truncated(format_args!("{}.{}.{}.{}", a, b, c, d)),
+            expires_in_secs: self
+                .expires
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        }
+    }
+}
+
+/// Shared with the status API
+static LEASES: Mutex<CriticalSectionRawMutex, RefCell<Vec<Lease, MAX_LEASES>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Clients holding an address right now
+pub fn active_leases() -> Vec<Lease, MAX_LEASES> {
+    let now = Instant::now();
+    LEASES.lock(|leases| {
+        leases
+            .borrow()
+            .iter()
+            .filter(|lease| lease.bound && lease.expires > now)
+            .copied()
+            .collect()
+    })
+}
+
+/// Forgets every client, for when the access point goes away
+pub fn clear_leases() {
+    LEASES.lock(|leases| leases.borrow_mut().clear());
+}
+
+/// Answers while the setup access point is up
+#[embassy_executor::task]
+pub async fn dhcp_server_task(stack: Stack<'static>, config: DhcpConfig) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; PACKET_LEN * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(SERVER_PORT) {
+        error!("DHCP server could not bind: {:?}", e);
+        return;
+    }
+    let [a, b, c, d] = config.pool_start;
+    info!(
+        "DHCP server handing out {} addresses from {}.{}.{}.{}",
+        config.pool_size, a, b, c, d
+    );
+
+    let mut request = [0; PACKET_LEN];
+    let mut reply = [0; PACKET_LEN];
+    loop {
+        let len = match socket.recv_from(&mut request).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                warn!("DHCP receive error: {:?}", e);
+                continue;
+            }
+        };
+        // Whatever network the device joined has its own DHCP server
+        if !wifi_supervisor::state().access_point_mode() {
+            continue;
+        }
+        let Some((reply_len, to)) = handle_message(&request[..len], &config, &mut reply) else {
+            continue;
+        };
+        let [a, b, c, d] = to;
+        let to = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(a, b, c, d)), CLIENT_PORT);
+        if let Err(e) = socket.send_to(&reply[..reply_len], to).await {
+            warn!("DHCP send error: {:?}", e);
+        }
+    }
+}
+
+/// The reply to a client message and the address to send it to, None when there is nothing
+/// to say
+fn handle_message(
+    request: &[u8],
+    config: &DhcpConfig,
+    reply: &mut [u8],
+) -> Option<(usize, [u8; 4])> {
+    // Only Ethernet addresses, and relays aren't supported
+    if request.len() < FIXED_LEN + MAGIC_COOKIE.len()
+        || request[0] != 1
+        || request[1] != 1
+        || request[2] != 6
+        || request[24..28] != [0; 4]
+        || request[FIXED_LEN..FIXED_LEN + 4] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let options = &request[FIXED_LEN + 4..];
+    let message_type = *find_option(options, OPTION_MESSAGE_TYPE)?.first()?;
+    let mac: [u8; 6] = request[28..34].try_into().ok()?;
+    let client_ip: [u8; 4] = request[12..16].try_into().ok()?;
+    let requested_ip = find_option(options, OPTION_REQUESTED_IP)
+        .and_then(|ip| <[u8; 4]>::try_from(ip).ok())
+        // Clients renewing a lease put the address in ciaddr instead
+        .or((client_ip != [0; 4]).then_some(client_ip));
+    let server_id = find_option(options, OPTION_SERVER_ID);
+    let now = Instant::now();
+
+    let (reply_type, ip) = LEASES.lock(|leases| {
+        let mut leases = leases.borrow_mut();
+        match message_type {
+            DISCOVER => {
+                let ip = allocate(&mut leases, config, mac, requested_ip, now)?;
+                Some((OFFER, ip))
+            }
+            // A request naming another server means the client took that server's offer
+            REQUEST if server_id.is_some_and(|id| id != config.server) => {
+                leases.retain(|lease| lease.mac != mac || lease.bound);
+                None
+            }
+            REQUEST => {
+                let lease = requested_ip.and_then(|ip| {
+                    leases
+                        .iter_mut()
+                        .find(|lease| lease.mac == mac && lease.ip == ip)
+                });
+                match lease {
+                    Some(lease) => {
+                        lease.expires = now + config.lease_time;
+                        lease.bound = true;
+                        let [a, b, c, d] = lease.ip;
+                        info!("DHCP lease {}.{}.{}.{} to {:02x}", a, b, c, d, mac);
+                        Some((ACK, lease.ip))
+                    }
+                    // Not an address this server offered, the client has to start over
+                    None => Some((NAK, [0; 4])),
+                }
+            }
+            RELEASE | DECLINE => {
+                leases.retain(|lease| lease.mac != mac);
+                None
+            }
+            _ => None,
+        }
+    })?;
+
+    let len = write_reply(request, config, reply_type, ip, reply)?;
+    // Renewing clients already have their address, everyone else can only hear broadcasts
+    let to = if reply_type == ACK && client_ip != [0; 4] {
+        client_ip
+    } else {
+        [255; 4]
+    };
+    Some((len, to))
+}
+
+/// The address for the client: the one it already has, the one it asked for if that is
+/// free, or the first free one in the pool
+fn allocate(
+    leases: &mut Vec<Lease, MAX_LEASES>,
+    config: &DhcpConfig,
+    mac: [u8; 6],
+    requested_ip: Option<[u8; 4]>,
+    now: Instant,
+) -> Option<[u8; 4]> {
+    if let Some(lease) = leases.iter_mut().find(|lease| lease.mac == mac) {
+        if !lease.bound {
+            lease.expires = now + OFFER_TIMEOUT;
+        }
+        return Some(lease.ip);
+    }
+    // Expired leases and stale offers make room
+    leases.retain(|lease| lease.expires > now);
+
+    let pool_size = (config.pool_size as usize).min(MAX_LEASES);
+    let in_pool = |ip: [u8; 4]| {
+        let offset = u32::from_be_bytes(ip).wrapping_sub(u32::from_be_bytes(config.pool_start));
+        (offset as usize) < pool_size
+    };
+    let is_free = |ip: [u8; 4]| !leases.iter().any(|lease| lease.ip == ip);
+    let ip = requested_ip
+        .filter(|&ip| in_pool(ip) && is_free(ip))
+        .or_else(|| {
+            (0..pool_size as u32)
+                .map(|offset| (u32::from_be_bytes(config.pool_start) + offset).to_be_bytes())
+                .find(|&ip| is_free(ip))
+        });
+    let Some(ip) = ip else {
+        warn!("DHCP pool is full");
+        return None;
+    };
+    leases
+        .push(Lease {
+            mac,
+            ip,
+            expires: now + OFFER_TIMEOUT,
+            bound: false,
+        })
+        .ok()?;
+    Some(ip)
+}
+
+fn write_reply(
+    request: &[u8],
+    config: &DhcpConfig,
+    message_type: u8,
+    ip: [u8; 4],
+    reply: &mut [u8],
+) -> Option<usize> {
+    reply.get_mut(..MIN_PACKET_LEN)?.fill(0);
+    reply[0] = BOOT_REPLY;
+    // htype, hlen and hops, then xid, secs and flags are the client's
+    reply[1..12].copy_from_slice(&request[1..12]);
+    reply[12..16].copy_from_slice(&request[12..16]);
+    reply[16..20].copy_from_slice(&ip);
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[FIXED_LEN..FIXED_LEN + 4].copy_from_slice(&MAGIC_COOKIE);
+
+    let mask = u32::MAX
+        .checked_shl(32 - config.prefix_len as u32)
+        .unwrap_or(0)
+        .to_be_bytes();
+    let lease_secs = config.lease_time.as_secs() as u32;
+    let mut options = OptionWriter {
+        buffer: reply,
+        len: FIXED_LEN + 4,
+    };
+    options.push(OPTION_MESSAGE_TYPE, &[message_type])?;
+    options.push(OPTION_SERVER_ID, &config.server)?;
+    if message_type != NAK {
+        options.push(OPTION_LEASE_TIME, &lease_secs.to_be_bytes())?;
+        options.push(OPTION_SUBNET_MASK, &mask)?;
+        options.push(OPTION_ROUTER, &config.server)?;
+        options.push(OPTION_DNS_SERVER, &config.server)?;
+    }
+    *options.buffer.get_mut(options.len)? = OPTION_END;
+    Some((options.len + 1).max(MIN_PACKET_LEN))
+}
+
+struct OptionWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl OptionWriter<'_> {
+    fn push(&mut self, code: u8, value: &[u8]) -> Option<()> {
+        let end = self.len + 2 + value.len();
+        let option = self.buffer.get_mut(self.len..end)?;
+        option[0] = code;
+        option[1] = value.len() as u8;
+        option[2..].copy_from_slice(value);
+        self.len = end;
+        Some(())
+    }
+}
+
+/// The value of the first option with the code
+fn find_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    loop {
+        match *options.first()? {
+            OPTION_END => return None,
+            OPTION_PAD => options = &options[1..],
+            found => {
+                let len = *options.get(1)? as usize;
+                let value = options.get(2..2 + len)?;
+                if found == code {
+                    return Some(value);
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+}
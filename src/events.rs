@@ -0,0 +1,57 @@
+//! Robot and system events, published from anywhere and streamed to clients over
+//! Server-Sent Events and the control WebSocket
+use defmt::*;
+use heapless::String;
+use http_server::events::{self as server_events, ServerEvent};
+use serde::Serialize;
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Sent when a client connects so it starts with the current state
+    Status {
+        light_on: bool,
+        access_point_mode: bool,
+        wifi_ssid: String<32>,
+        uptime_secs: u64,
+    },
+    CommandSent {
+        command: u8,
+    },
+    LightChanged {
+        on: bool,
+    },
+    WifiStatus {
+        connected: bool,
+        access_point_mode: bool,
+        wifi_ssid: String<32>,
+    },
+}
+
+impl Event {
+    /// The SSE `event:` name, matches the JSON `type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Status { .. } => "status",
+            Self::CommandSent { .. } => "command_sent",
+            Self::LightChanged { .. } => "light_changed",
+            Self::WifiStatus { .. } => "wifi_status",
+        }
+    }
+
+    /// The event as the server streams it
+    pub fn to_server_event(&self) -> Option<ServerEvent> {
+        let event = ServerEvent::new(self.name(), self);
+        if event.is_none() {
+            warn!("Event {} is too long to send", self.name());
+        }
+        event
+    }
+}
+
+/// Publishes to every subscriber without waiting, the oldest event is dropped for anyone too far behind
+pub fn publish(event: Event) {
+    if let Some(event) = event.to_server_event() {
+        server_events::publish(event);
+    }
+}
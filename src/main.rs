@@ -1,32 +1,114 @@
 #![no_std]
 #![no_main]
 
-use cyw43::{Control, JoinOptions};
+use assets::Asset;
+use auth::{Access, Auth, PasswordHash};
+use commands::RobotCommand;
+use core::cell::{Cell, RefCell};
+use core::net::IpAddr;
+use cyw43::Control;
 use cyw43_driver::{net_task, setup_cyw43};
 use defmt::*;
+use dhcp_server::DhcpConfig;
 use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
 use embassy_net::{Config, StackResources};
 use embassy_rp::{clocks::RoscRng, flash::Async, peripherals::FLASH, watchdog::Watchdog};
-use embassy_time::{Duration, Timer};
-use heapless::String;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use events::Event;
+use http_server::access_log::{AccessLog, ACCESS_LOG_LEN};
+use http_server::cors::Cors;
+use http_server::events::EVENTS;
+use http_server::io::template::{self, Value};
+use http_server::rate_limit::RateLimiter;
+use http_server::websocket::{close_code, Message, WebSocket};
 use http_server::{
-    HttpServer, Response, StatusCode, WebRequest, WebRequestHandler, WebRequestHandlerError,
+    ApiError, HttpServer, Method, Response, StatusCode, WebRequest, WebRequestHandler,
+    WebRequestHandlerError,
 };
-use io::easy_format_str;
+use mdns::{MdnsConfig, Service};
 use rand::RngCore;
-use save::{erase_save_flash, read_postcard_from_flash, save_postcard_to_flash, Save};
+use save::{read_postcard_from_flash, save_postcard_to_flash, Save, SavedNetwork};
 use static_cell::StaticCell;
+use wifi_supervisor::SupervisorConfig;
 use {defmt_rtt as _, panic_probe as _};
 
+mod api;
+mod assets;
+mod auth;
+mod captive_portal;
 mod commands;
 mod cyw43_driver;
+mod dhcp_server;
 mod env;
-mod http_server;
-mod io;
+mod events;
+mod mdns;
 mod robot_control;
 mod save;
+mod wifi;
+mod wifi_supervisor;
 
 const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Most times a single `/command/{n}?repeat=` request can send the command
+const MAX_COMMAND_REPEAT: u8 = 10;
+/// Commands each client can send at once before being slowed to one every `COMMAND_INTERVAL`.
+/// The robot can't act on them any faster than that anyway
+const COMMAND_BURST: u32 = 10;
+const COMMAND_INTERVAL: Duration = Duration::from_millis(200);
+/// How often an idle control WebSocket is pinged to check the client is still there
+const WEBSOCKET_PING_INTERVAL: Duration = Duration::from_secs(20);
+/// Reply to `/on` and `/off`
+const LIGHT_PAGE: &str = "<!DOCTYPE html>
+<html>
+    <body>
+        <h1>The light is {{light_status}}.</h1>
+    </body>
+</html>
+";
+/// Control panels hosted elsewhere use bearer tokens, so any origin is fine without credentials.
+/// List the panel origins and turn on `allow_credentials` to use the browser's Basic login instead
+const CORS: Cors = Cors {
+    allowed_origins: &["*"],
+    allowed_methods: "GET, POST, PUT, DELETE, OPTIONS",
+    allowed_headers: "Authorization, Content-Type, Accept",
+    allow_credentials: false,
+    max_age_secs: 600,
+};
+/// Addresses handed to clients of the setup access point
+const AP_DHCP: DhcpConfig = DhcpConfig {
+    server: captive_portal::AP_ADDRESS,
+    prefix_len: 24,
+    pool_start: [192, 168, 4, 100],
+    pool_size: 8,
+    lease_time: Duration::from_secs(2 * 60 * 60),
+};
+/// Lets the device be found as `picosapien.local` once it's on a network
+const MDNS: MdnsConfig = MdnsConfig {
+    hostname: "picosapien",
+    services: &[Service {
+        instance: "Picosapien",
+        service: "_http._tcp",
+        port: 80,
+        txt: &["path=/"],
+    }],
+};
+/// How the device stays on Wi-Fi, and when it gives up and runs the setup access point
+const WIFI_SUPERVISOR: SupervisorConfig = SupervisorConfig {
+    check_interval: Duration::from_secs(2),
+    ap_fallback_after: Duration::from_secs(2 * 60),
+    min_backoff: Duration::from_secs(1),
+    max_backoff: Duration::from_secs(30),
+    dhcp_timeout: Duration::from_secs(15),
+    ap_rescan_interval: Duration::from_secs(5 * 60),
+};
+/// Recent requests for `/api/v1/logs` and `/api/logs`, so there's a record of who sent
+/// which command
+static ACCESS_LOG: AccessLog = AccessLog::new();
 
 #[embassy_executor::task]
 async fn watchdog_task(mut watchdog: Watchdog) {
@@ -53,11 +135,13 @@ async fn main(spawner: Spawner) {
 
     let robot_control = robot_control::RobotControl::new(p.PIN_16.into());
 
-    let mut turn_on_ap = false;
+    // What's in flash once booted, kept so saving one setting doesn't wipe the others
+    let mut saved = Save::default();
     let join_another_net_work_config = Config::dhcpv4(Default::default());
 
-    // Init network stack
-    static RESOURCES: StaticCell<StackResources<4>> = StaticCell::new();
+    // Init network stack, with room for every HTTP socket plus DHCP, DNS, mDNS and the
+    // captive portal's DNS and DHCP servers
+    static RESOURCES: StaticCell<StackResources<9>> = StaticCell::new();
     let (stack, runner) = embassy_net::new(
         net_device,
         join_another_net_work_config,
@@ -69,111 +153,490 @@ async fn main(spawner: Spawner) {
     control.gpio_set(0, true).await;
     // erase_save_flash(&mut flash);
     let request_to_read_flash = read_postcard_from_flash(&mut flash);
+    // Saved networks that can't be joined are no reason to wipe the settings, the Wi-Fi
+    // supervisor falls back to the setup access point by itself
     match request_to_read_flash {
-        Ok(mut save) => {
-            watchdog.start(Duration::from_secs(8));
-
-            //If the last save says clear on boot. Clear and restart
-            if save.clear_on_boot {
-                info!("Clear on boot flag set. Clearing flash and restarting");
-                erase_save_flash(&mut flash);
-                watchdog.trigger_reset();
-            }
-            //Sets a clear on boot flag before attempting to connect so we know if its never cleared we need to restart with a clear flash
-
-            let _ = save_postcard_to_flash(
-                &mut flash,
-                &Save {
-                    clear_on_boot: true,
-                    wifi_ssid: String::new(),
-                    wifi_password: String::new(),
-                },
-            );
-            let mut wifi_connection_attempts = 0;
-            let mut was_able_to_connect = false;
-            while wifi_connection_attempts < 30 {
-                debug!("Attempting to connect to wifi: {}", save.wifi_ssid);
-                let attempt_to_connect = control
-                    .join(
-                        save.wifi_ssid.as_str(),
-                        JoinOptions::new(save.wifi_password.as_bytes()),
-                    )
-                    .await;
-                match attempt_to_connect {
-                    Ok(_) => {
-                        info!("join successful");
-                        was_able_to_connect = true;
-                        break;
-                    }
-                    Err(err) => {
-                        error!("join failed with status={}", err.status);
-                    }
-                }
-                Timer::after(Duration::from_secs(1)).await;
-                wifi_connection_attempts += 1;
-            }
-            if was_able_to_connect {
-                save.clear_on_boot = false;
-                let _ = save_postcard_to_flash(&mut flash, &save);
-            } else {
-                turn_on_ap = true;
-            }
-            //Spawn watch dog task
-            watchdog.feed();
-        }
+        Ok(save) => saved = save,
         Err(err) => {
             error!("Error reading flash: {:?}", err);
-            turn_on_ap = true;
         }
     }
+    watchdog.start(Duration::from_secs(8));
+    spawner.must_spawn(watchdog_task(watchdog));
 
-    if turn_on_ap {
-        info!("Could not connect to save connection bringing up AP");
-        // Use a link-local address for communication without DHCP server
-        stack.set_config_v4(embassy_net::ConfigV4::Static(embassy_net::StaticConfigV4 {
-            address: embassy_net::Ipv4Cidr::new(embassy_net::Ipv4Address::new(169, 254, 1, 1), 16),
-            dns_servers: heapless::Vec::new(),
-            gateway: None,
-        }));
-        control.start_ap_open("Picosapien", 5).await;
+    // The Wi-Fi chip drops multicast it hasn't been told about
+    if let Err(e) = control.add_multicast_address(mdns::MULTICAST_MAC).await {
+        warn!("Could not listen for mDNS: {:?}", e);
     }
-    info!("waiting for DHCP...");
-    while !stack.is_config_up() {
-        Timer::after_millis(100).await;
-    }
-    info!("DHCP is now up!");
-    //We can stop manually feeding the watchdog now
-    spawner.must_spawn(watchdog_task(watchdog));
+    // These only answer in the mode they're for, the supervisor switches between them
+    spawner.must_spawn(captive_portal::dns_task(stack));
+    spawner.must_spawn(dhcp_server::dhcp_server_task(stack, AP_DHCP));
+    spawner.must_spawn(mdns::mdns_task(stack, MDNS));
 
-    let mut server = HttpServer::new(80, stack);
+    let server = HttpServer::new()
+        .with_cors(CORS)
+        .with_auth_challenge(auth::REALM)
+        .with_access_log(&ACCESS_LOG);
+    let handler = WebsiteHandler {
+        auth: Auth::new(saved.admin_password),
+        saved: RefCell::new(saved),
+        control: Mutex::new(control),
+        flash: Mutex::new(flash),
+        robot_control: Mutex::new(robot_control),
+        light_on: Cell::new(true),
+        command_limiter: RateLimiter::new(COMMAND_BURST, COMMAND_INTERVAL),
+    };
 
-    server
-        .serve(WebsiteHandler {
-            control,
-            flash,
-            robot_control,
-        })
-        .await;
+    join(
+        server.serve(stack, 80, &handler),
+        wifi_supervisor::supervise(&handler, stack, WIFI_SUPERVISOR),
+    )
+    .await;
 }
 
+/// Shared by every HTTP connection, so anything that changes is behind a `Mutex` or `Cell`
 struct WebsiteHandler {
-    control: Control<'static>,
-    flash: embassy_rp::flash::Flash<'static, FLASH, Async, FLASH_SIZE>,
-    robot_control: robot_control::RobotControl<'static>,
+    auth: Auth,
+    /// Settings as last written to flash
+    saved: RefCell<Save>,
+    control: Mutex<NoopRawMutex, Control<'static>>,
+    flash: Mutex<NoopRawMutex, embassy_rp::flash::Flash<'static, FLASH, Async, FLASH_SIZE>>,
+    robot_control: Mutex<NoopRawMutex, robot_control::RobotControl<'static>>,
+    light_on: Cell<bool>,
+    /// Shared by the HTTP command routes and the control WebSocket
+    command_limiter: RateLimiter<8>,
+}
+
+impl WebsiteHandler {
+    /// DHCP leases are only in there for `Access::Admin`
+    fn status(&self, access: Access) -> api::StatusResponse {
+        let wifi = wifi_supervisor::state();
+        api::StatusResponse {
+            light_on: self.light_on.get(),
+            access_point_mode: wifi.access_point_mode(),
+            wifi_ssid: wifi.ssid,
+            uptime_secs: Instant::now().as_secs(),
+            admin_password_set: self.auth.password_set(),
+            dhcp_leases: (access == Access::Admin).then(|| {
+                dhcp_server::active_leases()
+                    .iter()
+                    .map(|lease| lease.to_api())
+                    .collect()
+            }),
+        }
+    }
+
+    fn status_event(&self) -> Event {
+        let wifi = wifi_supervisor::state();
+        Event::Status {
+            light_on: self.light_on.get(),
+            access_point_mode: wifi.access_point_mode(),
+            wifi_ssid: wifi.ssid,
+            uptime_secs: Instant::now().as_secs(),
+        }
+    }
+
+    /// Who can use each route. `/api/v1/auth/password` is open until the first password is set
+    fn required_access(&self, path: &str) -> Access {
+        match path {
+            "/api/v1/auth/password" if !self.auth.password_set() => Access::Public,
+            "/SaveWifi"
+            | "/api/v1/config"
+            | "/api/v1/auth/password"
+            | "/api/v1/auth/token"
+            | "/api/v1/logs"
+            | "/api/logs"
+            | "/api/v1/wifi/scan"
+            | "/api/v1/wifi/networks"
+            | "/api/v1/wifi/networks/order" => Access::Admin,
+            "/on" | "/off" | "/events" | "/api/v1/command" | "/api/v1/ws" => Access::Operator,
+            path if path.starts_with("/command") => Access::Operator,
+            _ => Access::Public,
+        }
+    }
+
+    /// Changes the saved settings and writes them to flash, the copy in RAM is only
+    /// updated once the write worked. Nothing is written when `update` fails
+    async fn update_save(
+        &self,
+        update: impl FnOnce(&mut Save) -> Result<(), &'static str>,
+    ) -> Result<(), WebRequestHandlerError> {
+        let mut flash = self.flash.lock().await;
+        let mut save = self.saved.borrow().clone();
+        update(&mut save).map_err(WebRequestHandlerError::BadRequest)?;
+        save_postcard_to_flash(&mut flash, &save)
+            .map_err(|_| WebRequestHandlerError::Internal("Error saving settings to flash"))?;
+        *self.saved.borrow_mut() = save;
+        Ok(())
+    }
+
+    /// The setup page's network goes first, it's the one the user is trying to get on
+    async fn save_wifi(&self, config: api::ConfigRequest) -> Result<(), WebRequestHandlerError> {
+        let network = SavedNetwork {
+            ssid: config.wifi_ssid,
+            password: config.wifi_password,
+        };
+        self.update_save(|save| save.add_network(network, Some(0)))
+            .await?;
+        wifi_supervisor::networks_changed();
+        Ok(())
+    }
+
+    fn saved_networks<'a>(&self, response_buffer: &'a mut [u8]) -> Response<'a> {
+        let saved = self.saved.borrow();
+        Response::new_json_value(
+            StatusCode::Ok,
+            &api::SavedNetworksResponse {
+                networks: saved
+                    .networks
+                    .iter()
+                    .enumerate()
+                    .map(|(priority, network)| api::SavedNetworkInfo {
+                        ssid: network.ssid.as_str(),
+                        priority,
+                    })
+                    .collect(),
+                last_connected: saved.last_connected.as_deref(),
+            },
+            response_buffer,
+        )
+    }
+
+    /// Uses up `times` of the client's command allowance. Stop always goes through, the web
+    /// app sends it to end hold-to-walk and a throttled client still has to be able to stop
+    fn command_allowed(&self, ip: Option<IpAddr>, command: u8, times: u32) -> bool {
+        command == RobotCommand::Stop as u8 || self.command_limiter.check_n(ip, times)
+    }
+
+    async fn send_command(&self, command: u8) {
+        self.robot_control
+            .lock()
+            .await
+            .send_raw_command(command)
+            .await;
+        events::publish(Event::CommandSent { command });
+    }
+
+    async fn set_light(&self, on: bool) {
+        self.control.lock().await.gpio_set(0, on).await;
+        self.light_on.set(on);
+        events::publish(Event::LightChanged { on });
+    }
+
+    async fn handle_api_v1<'a>(
+        &'a self,
+        path: &str,
+        request: WebRequest<'_, '_>,
+        response_buffer: &'a mut [u8],
+    ) -> Result<Response<'a>, WebRequestHandlerError> {
+        let response = match (request.method, path) {
+            (Some(Method::Post), "/command") => {
+                let command_request = request.json::<api::CommandRequest>()?;
+                let remote = request.remote.map(|remote| remote.ip());
+                if !self.command_allowed(remote, command_request.command, 1) {
+                    return Err(WebRequestHandlerError::TooManyRequests);
+                }
+                info!("Command: {:?}", command_request.command);
+                self.send_command(command_request.command).await;
+                Response::new_json_value(
+                    StatusCode::Ok,
+                    &api::CommandResponse {
+                        sent: command_request.command,
+                    },
+                    response_buffer,
+                )
+            }
+            (Some(Method::Get), "/status") => {
+                let status = self.status(self.auth.access(&request));
+                Response::new_json_value(StatusCode::Ok, &status, response_buffer)
+            }
+            (Some(Method::Get), "/logs") => {
+                let limit = match request.query_param("limit") {
+                    Some(limit) => limit
+                        .parse::<usize>()
+                        .ok_or(WebRequestHandlerError::BadRequest("Cannot parse limit"))?,
+                    None => ACCESS_LOG_LEN,
+                };
+                let json = ACCESS_LOG
+                    .write_json(limit, response_buffer)
+                    .map_err(|_| WebRequestHandlerError::Internal("Access log does not fit"))?;
+                Response::new_with_content_type(StatusCode::Ok, "application/json", json)
+            }
+            (Some(Method::Get), "/wifi/scan") => {
+                let networks = wifi::scan(&mut *self.control.lock().await).await;
+                info!("Wi-Fi scan found {} networks", networks.len());
+                Response::new_json_value(
+                    StatusCode::Ok,
+                    &api::WifiScanResponse { networks },
+                    response_buffer,
+                )
+            }
+            (Some(Method::Get), "/wifi/networks") => self.saved_networks(response_buffer),
+            (Some(Method::Post), "/wifi/networks") => {
+                let add_request = request.json::<api::AddNetworkRequest>()?;
+                let network = SavedNetwork {
+                    ssid: add_request.ssid,
+                    password: add_request.password,
+                };
+                self.update_save(|save| save.add_network(network, add_request.priority))
+                    .await?;
+                wifi_supervisor::networks_changed();
+                self.saved_networks(response_buffer)
+            }
+            (Some(Method::Delete), "/wifi/networks") => {
+                let ssid = request
+                    .query_param("ssid")
+                    .and_then(|ssid| ssid.to_string::<32>())
+                    .ok_or(WebRequestHandlerError::BadRequest(
+                        "Missing or too long ssid",
+                    ))?;
+                self.update_save(|save| save.remove_network(&ssid)).await?;
+                self.saved_networks(response_buffer)
+            }
+            (Some(Method::Put), "/wifi/networks/order") => {
+                let order = request.json::<api::ReorderNetworksRequest>()?;
+                self.update_save(|save| save.reorder_networks(&order.ssids))
+                    .await?;
+                self.saved_networks(response_buffer)
+            }
+            (Some(Method::Get), "/config") => Response::new_json_value(
+                StatusCode::Ok,
+                &api::ConfigResponse {
+                    wifi_ssid: wifi_supervisor::state().ssid.as_str(),
+                    restart_required: false,
+                },
+                response_buffer,
+            ),
+            (Some(Method::Post), "/config") => {
+                let config = request.json::<api::ConfigRequest>()?;
+                self.save_wifi(config).await?;
+                Response::new_json_value(
+                    StatusCode::Ok,
+                    &api::ConfigResponse {
+                        wifi_ssid: self.saved.borrow().networks[0].ssid.as_str(),
+                        restart_required: wifi_supervisor::state().connected(),
+                    },
+                    response_buffer,
+                )
+            }
+            (Some(Method::Post), "/auth/password") => {
+                let password_request = request.json::<api::PasswordRequest>()?;
+                if password_request.password.len() < auth::MIN_PASSWORD_LEN {
+                    return Err(WebRequestHandlerError::BadRequest(
+                        "Password must be at least 8 characters",
+                    ));
+                }
+                let password = PasswordHash::new(&password_request.password);
+                self.update_save(|save| {
+                    save.admin_password = Some(password);
+                    Ok(())
+                })
+                .await?;
+                self.auth.set_password(password);
+                info!("Admin password changed");
+                let status = self.status(self.auth.access(&request));
+                Response::new_json_value(StatusCode::Ok, &status, response_buffer)
+            }
+            (Some(Method::Post), "/auth/token") => {
+                let token_request = request.json::<api::TokenRequest>()?;
+                if token_request.access == Access::Public {
+                    return Err(WebRequestHandlerError::BadRequest(
+                        "Tokens are for operator or admin access",
+                    ));
+                }
+                let token = self.auth.issue_token(token_request.access);
+                Response::new_json_value(
+                    StatusCode::Ok,
+                    &api::TokenResponse {
+                        token: token.as_str(),
+                        access: token_request.access,
+                    },
+                    response_buffer,
+                )
+            }
+            (_, "/ws") => {
+                return Err(WebRequestHandlerError::BadRequest(
+                    "Expected a WebSocket upgrade",
+                ))
+            }
+            (
+                _,
+                "/command"
+                | "/status"
+                | "/config"
+                | "/logs"
+                | "/wifi/scan"
+                | "/wifi/networks"
+                | "/wifi/networks/order"
+                | "/auth/password"
+                | "/auth/token",
+            ) => return Err(WebRequestHandlerError::MethodNotAllowed),
+            _ => return Err(WebRequestHandlerError::NotFound),
+        };
+        Ok(response)
+    }
 }
 
 impl WebRequestHandler for WebsiteHandler {
+    /// Browsers can't set headers on WebSockets, so the web app passes a token as `?access_token=`
+    fn accepts_websocket(&self, request: &WebRequest<'_, '_>) -> bool {
+        request.path == Some("/api/v1/ws") && self.auth.authorize(request, Access::Operator).is_ok()
+    }
+
+    /// Panels on other origins can use a token that's good enough for the route. Routes
+    /// anyone can use never count, or any page could set the first admin password
+    fn trusts_cross_origin(&self, request: &WebRequest<'_, '_>) -> bool {
+        let required = self.required_access(request.path.unwrap_or(""));
+        required > Access::Public && self.auth.token_access_of(request) >= required
+    }
+
+    /// Control channel for the web app. Text frames are `{"command": n}` JSON and a binary
+    /// frame of a single byte is the raw command. Every event from the event bus is pushed back
+    async fn handle_websocket<S: Read + Write>(&self, websocket: &mut WebSocket<'_, S>) {
+        let mut event_buffer = [0u8; 256];
+        let Ok(mut subscriber) = EVENTS.subscriber() else {
+            warn!("No event subscribers left for the WebSocket");
+            websocket.close(close_code::TRY_AGAIN_LATER).await;
+            return;
+        };
+        if websocket
+            .send_json(&self.status_event(), &mut event_buffer)
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        loop {
+            let next = with_timeout(
+                WEBSOCKET_PING_INTERVAL,
+                select(websocket.read(), subscriber.next_message()),
+            )
+            .await;
+
+            let command = match next {
+                Ok(Either::First(Ok(Message::Binary(&[command])))) => Some(command),
+                Ok(Either::First(Ok(Message::Text(text)))) => {
+                    serde_json_core::from_str::<api::CommandRequest>(text)
+                        .ok()
+                        .map(|(command_request, _)| command_request.command)
+                }
+                Ok(Either::First(Ok(Message::Binary(_)))) => None,
+                Ok(Either::First(Err(err))) => {
+                    info!("WebSocket closed: {:?}", err);
+                    return;
+                }
+                Ok(Either::Second(WaitResult::Message(event))) => {
+                    if websocket.send_text(&event.data).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Ok(Either::Second(WaitResult::Lagged(missed))) => {
+                    warn!("WebSocket missed {} events", missed);
+                    continue;
+                }
+                Err(_) => {
+                    // Nothing from the client for a while, not even a pong
+                    if websocket.last_received().elapsed() > WEBSOCKET_PING_INTERVAL * 2 {
+                        info!("WebSocket client stopped responding");
+                        websocket.close(close_code::GOING_AWAY).await;
+                        return;
+                    }
+                    if websocket.ping(b"").await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            let remote = websocket.remote().map(|remote| remote.ip());
+            match command {
+                Some(command) if !self.command_allowed(remote, command, 1) => {
+                    let error = ApiError {
+                        error: "too_many_requests",
+                        message: "Too many commands, slow down",
+                    };
+                    if websocket
+                        .send_json(&error, &mut event_buffer)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Some(command) => {
+                    info!("WebSocket command: {:?}", command);
+                    // The command_sent event comes back through the subscriber
+                    self.send_command(command).await;
+                }
+                None => {
+                    let error = ApiError {
+                        error: "bad_request",
+                        message: "Expected {\"command\": n} or a single byte",
+                    };
+                    if websocket
+                        .send_json(&error, &mut event_buffer)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
     async fn handle_request<'a>(
-        &mut self,
+        &'a self,
         request: WebRequest<'_, '_>,
         response_buffer: &'a mut [u8],
     ) -> Result<Response<'a>, WebRequestHandlerError> {
+        let path = request.path.unwrap();
+        // Joining the setup network opens the Wi-Fi page. It still asks for the admin
+        // password first if that hasn't been set
+        if wifi_supervisor::state().access_point_mode()
+            && captive_portal::is_connectivity_probe(path)
+        {
+            return Ok(Response::new_redirect(captive_portal::PORTAL_URL));
+        }
+        // Nothing but setting the admin password works until it has been set
+        if !self.auth.password_set()
+            && !matches!(path, "/setup" | "/api/v1/auth/password" | "/api/v1/status")
+        {
+            if request.accepts_json() {
+                return Err(WebRequestHandlerError::Forbidden(
+                    "Set the admin password at /setup first",
+                ));
+            }
+            return Ok(Response::new_redirect("/setup"));
+        }
+        self.auth.authorize(&request, self.required_access(path))?;
+        // Anything that changes the robot or the settings has to be a POST, so a link or an
+        // `<img>` on another page can't trigger it. Checked before the rate limit so a wrong
+        // method doesn't use up the client's allowance
+        if matches!(path, "/on" | "/off" | "/SaveWifi") || path.starts_with("/command") {
+            if request.method != Some(Method::Post) {
+                return Err(WebRequestHandlerError::MethodNotAllowed);
+            }
+        }
+        if is_light_route(path) && !self.command_limiter.check(request.remote.map(|r| r.ip())) {
+            return Err(WebRequestHandlerError::TooManyRequests);
+        }
+
+        if let Some(api_path) = request.path.unwrap().strip_prefix("/api/v1") {
+            return self.handle_api_v1(api_path, request, response_buffer).await;
+        }
+        // The access log's first address, before the API was versioned
+        if path == "/api/logs" {
+            return self.handle_api_v1("/logs", request, response_buffer).await;
+        }
+
         if request.path.unwrap().starts_with("/command") {
             let extracted_command = request.path.unwrap().split("/command/").last();
             if extracted_command.is_none() {
                 error!("No command found");
-                return Ok(Response::new_html(
-                    StatusCode::Ok,
+                return Err(WebRequestHandlerError::BadRequest(
                     "No command found in the request",
                 ));
             }
@@ -182,72 +645,90 @@ impl WebRequestHandler for WebsiteHandler {
             let parse_command = command.parse::<u8>();
             if parse_command.is_err() {
                 error!("Cannot parse command");
-                return Ok(Response::new_html(
-                    StatusCode::Ok,
+                return Err(WebRequestHandlerError::BadRequest(
                     "Cannot parse command to u8",
                 ));
             }
             let command = command.parse::<u8>().unwrap();
-            self.robot_control.send_raw_command(command).await;
+            let repeat = match request.query_param("repeat") {
+                Some(repeat) => repeat
+                    .parse::<u8>()
+                    .ok_or(WebRequestHandlerError::BadRequest(
+                        "Cannot parse repeat to u8",
+                    ))?,
+                None => 1,
+            };
+            if repeat > MAX_COMMAND_REPEAT {
+                return Err(WebRequestHandlerError::BadRequest("Repeat is too large"));
+            }
+            // Every repeat is a command sent, so every one counts against the limit
+            if !self.command_allowed(request.remote.map(|r| r.ip()), command, repeat.into()) {
+                return Err(WebRequestHandlerError::TooManyRequests);
+            }
+            for _ in 0..repeat {
+                self.send_command(command).await;
+            }
             return Ok(Response::new_html(StatusCode::Ok, "Command sent"));
         }
 
         let light_status = match request.path.unwrap() {
-            "/" => {
-                let web_app = include_str!("../web_app/index.html");
-                return Ok(Response::new_html(StatusCode::Ok, web_app));
-            }
-            "/wifi" => {
-                let wifi_page = include_str!("../web_app/wifi.html");
-                return Ok(Response::new_html(StatusCode::Ok, wifi_page));
-            }
             "/SaveWifi" => {
-                let result = serde_json_core::from_str::<Save>(request.body);
-
-                if result.is_err() {
-                    return Ok(Response::new_html(
-                        StatusCode::BadRequest,
-                        "Error parsing json from request",
-                    ));
-                }
-
-                let (save, _) = result.unwrap();
+                // Plain HTML form posts work without JavaScript, the setup page sends JSON
+                let config = if request.is_form() {
+                    let form = request.form_params()?;
+                    api::ConfigRequest {
+                        wifi_ssid: form
+                            .get("wifi_ssid")
+                            .and_then(|ssid| ssid.to_string::<32>())
+                            .ok_or(WebRequestHandlerError::BadRequest(
+                                "Missing or too long wifi_ssid",
+                            ))?,
+                        wifi_password: form
+                            .get("wifi_password")
+                            .and_then(|password| password.to_string::<32>())
+                            .ok_or(WebRequestHandlerError::BadRequest(
+                                "Missing or too long wifi_password",
+                            ))?,
+                    }
+                } else {
+                    request.json::<api::ConfigRequest>()?
+                };
 
-                let save_result = save_postcard_to_flash(&mut self.flash, &save);
-                if save_result.is_err() {
-                    return Ok(Response::new_html(
-                        StatusCode::InternalServerError,
-                        "Error saving wifi credentials to flash",
-                    ));
-                }
+                self.save_wifi(config).await?;
                 return Ok(Response::new_html(StatusCode::Ok, "Wifi has been saved"));
             }
+            "/events" => {
+                return Response::new_event_stream(self.status_event().to_server_event());
+            }
             "/on" => {
-                self.control.gpio_set(0, true).await;
+                self.set_light(true).await;
                 "on"
             }
             "/off" => {
-                self.control.gpio_set(0, false).await;
+                self.set_light(false).await;
                 "off"
             }
-            _ => "Probably off",
+            // Pages, scripts and styles from web_app/
+            path => {
+                return Asset::find(path)
+                    .map(|asset| asset.response(&request))
+                    .ok_or(WebRequestHandlerError::NotFound)
+            }
         };
 
-        let html_response = easy_format_str(
-            format_args!(
-                "
-            <!DOCTYPE html>
-            <html>
-                <body>
-                    <h1>The light is {light_status}.</h1>
-                    
-                </body>
-            </html>
-            "
-            ),
+        let html_response = template::render_to_str(
+            LIGHT_PAGE,
+            &[("light_status", Value::Str(light_status))],
             response_buffer,
-        );
+        )
+        .map_err(WebRequestHandlerError::Internal)?;
 
-        Ok(Response::new_html(StatusCode::Ok, html_response.unwrap()))
+        Ok(Response::new_html(StatusCode::Ok, html_response))
     }
 }
+
+/// Routes that switch the light share the command rate limit. Robot commands are
+/// limited where they're handled, once it's known which command it is and how often it repeats
+fn is_light_route(path: &str) -> bool {
+    matches!(path, "/on" | "/off")
+}
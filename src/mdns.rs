@@ -0,0 +1,439 @@
+//! Multicast DNS (RFC 6762) and DNS-SD (RFC 6763), so the device can be opened as
+//! `http://<hostname>.local` and shows up in service browsers once it has joined a network.
+//! Only IPv4 and the records needed for that are answered, always to the multicast group.
+//! There is no conflict detection and no replies to resolvers that aren't mDNS aware
+use core::net::Ipv4Addr;
+use defmt::*;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration};
+use heapless::String;
+use http_server::io::{format_truncated, try_format};
+
+use crate::wifi_supervisor;
+
+/// Ethernet address the Wi-Fi chip has to accept for the mDNS group
+pub const MULTICAST_MAC: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+const MULTICAST_GROUP: [u8; 4] = [224, 0, 0, 251];
+const MDNS_PORT: u16 = 5353;
+const PACKET_LEN: usize = 512;
+const HEADER_LEN: usize = 12;
+/// More services than this are ignored, each takes four bits of `Records`
+const MAX_SERVICES: usize = 7;
+
+/// TTLs RFC 6762 recommends, host records are short since the address can change
+const HOST_TTL_SECS: u32 = 120;
+const SERVICE_TTL_SECS: u32 = 4500;
+/// Announcements sent after getting an address, and the time between them
+const ANNOUNCEMENTS: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on records only this device answers for, so caches replace what they had
+const CACHE_FLUSH: u16 = 0x8000;
+
+const SERVICES_NAME: &str = "_services._dns-sd._udp.local";
+
+#[derive(Clone, Copy)]
+pub struct MdnsConfig {
+    /// Answered as `<hostname>.local`
+    pub hostname: &'static str,
+    pub services: &'static [Service],
+}
+
+/// A DNS-SD service, advertised as `<instance>.<service>.local`
+pub struct Service {
+    /// Name shown in service browsers, without dots
+    pub instance: &'static str,
+    /// Like `_http._tcp`
+    pub service: &'static str,
+    pub port: u16,
+    /// `key=value` pairs, like `path=/`
+    pub txt: &'static [&'static str],
+}
+
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>, config: MdnsConfig) {
+    let [a, b, c, d] = MULTICAST_GROUP;
+    let group = Ipv4Address::new(a, b, c, d);
+    if let Err(e) = stack.join_multicast_group(IpAddress::Ipv4(group)) {
+        error!("mDNS could not join the multicast group: {:?}", e);
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; PACKET_LEN * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        error!("mDNS could not bind: {:?}", e);
+        return;
+    }
+    let multicast = IpEndpoint::new(IpAddress::Ipv4(group), MDNS_PORT);
+    info!("mDNS answering for {}.local", config.hostname);
+
+    let mut query = [0; PACKET_LEN];
+    let mut response = [0; PACKET_LEN];
+
+    let mut announced = None;
+    let mut announcements_left = 0;
+    loop {
+        // Tell everyone whenever the device gets on a network, twice in case one is lost
+        let address = connected_address(stack);
+        if address != announced {
+            announced = address;
+            announcements_left = ANNOUNCEMENTS;
+        }
+        if let Some(address) = address.filter(|_| announcements_left > 0) {
+            announcements_left -= 1;
+            if let Some(len) = announcement(&config, address, &mut response) {
+                if let Err(e) = socket.send_to(&response[..len], multicast).await {
+                    warn!("mDNS announcement failed: {:?}", e);
+                }
+            }
+        }
+
+        // Wakes up every second to notice a new address
+        let Ok(received) = with_timeout(ANNOUNCE_INTERVAL, socket.recv_from(&mut query)).await
+        else {
+            continue;
+        };
+        let len = match received {
+            Ok((len, _)) => len,
+            Err(e) => {
+                warn!("mDNS receive error: {:?}", e);
+                continue;
+            }
+        };
+        let Some(address) = connected_address(stack) else {
+            continue;
+        };
+        let Some(response_len) = answer(&query[..len], &config, address, &mut response) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response[..response_len], multicast).await {
+            warn!("mDNS send error: {:?}", e);
+        }
+    }
+}
+
+/// The address on the joined network, None while running the setup access point since
+/// its clients are sent to the device by the captive portal instead
+fn connected_address(stack: Stack<'static>) -> Option<[u8; 4]> {
+    if !wifi_supervisor::state().connected() {
+        return None;
+    }
+    own_address(stack)
+}
+
+/// The stack's IPv4 address. Goes through the text form, which is the same whichever
+/// address type embassy-net uses
+fn own_address(stack: Stack<'static>) -> Option<[u8; 4]> {
+    let address = stack.config_v4()?.address.address();
+    let text = try_format::<16>(format_args!("{}", address)).ok()?;
+    Some(text.parse::<Ipv4Addr>().ok()?.octets())
+}
+
+/// Which records go in a response. Bit 0 is the A record, then four bits per service for
+/// its entry in the service list, its PTR, SRV and TXT
+#[derive(Clone, Copy, Default)]
+struct Records(u32);
+
+impl Records {
+    const A: u32 = 1;
+    const SERVICES_PTR: u32 = 0;
+    const PTR: u32 = 1;
+    const SRV: u32 = 2;
+    const TXT: u32 = 3;
+
+    fn service(index: usize, record: u32) -> u32 {
+        1 << (1 + index * 4 + record as usize)
+    }
+
+    fn contains(&self, bits: u32) -> bool {
+        self.0 & bits != 0
+    }
+}
+
+/// Every record, sent when the device appears on the network
+fn announcement(config: &MdnsConfig, address: [u8; 4], response: &mut [u8]) -> Option<usize> {
+    let mut records = Records(Records::A);
+    for (index, _) in config.services.iter().take(MAX_SERVICES).enumerate() {
+        for record in [Records::PTR, Records::SRV, Records::TXT] {
+            records.0 |= Records::service(index, record);
+        }
+    }
+    write_response(config, address, records, Records::default(), response)
+}
+
+/// The response to `query`, None when it asks nothing this device answers for
+fn answer(
+    query: &[u8],
+    config: &MdnsConfig,
+    address: [u8; 4],
+    response: &mut [u8],
+) -> Option<usize> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    if flags & FLAG_RESPONSE != 0 {
+        return None;
+    }
+    let question_count = u16::from_be_bytes([query[4], query[5]]);
+    let host: String<80> = format_truncated(format_args!("{}.local", config.hostname));
+
+    let mut answers = Records::default();
+    let mut additional = Records::default();
+    let mut offset = HEADER_LEN;
+    for _ in 0..question_count {
+        let (name, next) = read_name(query, offset)?;
+        let record_type = u16::from_be_bytes([*query.get(next)?, *query.get(next + 1)?]);
+        offset = next + 4;
+        let wants = |wanted: u16| record_type == wanted || record_type == TYPE_ANY;
+
+        if name.eq_ignore_ascii_case(&host) && wants(TYPE_A) {
+            answers.0 |= Records::A;
+        }
+        for (index, service) in config.services.iter().take(MAX_SERVICES).enumerate() {
+            let bit = |record| Records::service(index, record);
+            let service_name: String<80> =
+                format_truncated(format_args!("{}.local", service.service));
+            let instance_name: String<160> = format_truncated(format_args!(
+                "{}.{}.local",
+                service.instance, service.service
+            ));
+            if name.eq_ignore_ascii_case(SERVICES_NAME) && wants(TYPE_PTR) {
+                answers.0 |= bit(Records::SERVICES_PTR);
+            }
+            if name.eq_ignore_ascii_case(&service_name) && wants(TYPE_PTR) {
+                answers.0 |= bit(Records::PTR);
+                additional.0 |= bit(Records::SRV) | bit(Records::TXT) | Records::A;
+            }
+            if name.eq_ignore_ascii_case(&instance_name) {
+                if wants(TYPE_SRV) {
+                    answers.0 |= bit(Records::SRV);
+                    additional.0 |= Records::A;
+                }
+                if wants(TYPE_TXT) {
+                    answers.0 |= bit(Records::TXT);
+                }
+            }
+        }
+    }
+    if answers.0 == 0 {
+        return None;
+    }
+    additional.0 &= !answers.0;
+    write_response(config, address, answers, additional, response)
+}
+
+/// Multicast responses have no ID and don't repeat the questions
+fn write_response(
+    config: &MdnsConfig,
+    address: [u8; 4],
+    answers: Records,
+    additional: Records,
+    response: &mut [u8],
+) -> Option<usize> {
+    let mut writer = Writer {
+        buffer: response,
+        len: 0,
+    };
+    writer.u16(0)?;
+    writer.u16(FLAG_RESPONSE | FLAG_AUTHORITATIVE)?;
+    writer.u16(0)?;
+    let counts_at = writer.len;
+    // Answer and additional counts are filled in once the records are written
+    writer.bytes(&[0; 6])?;
+
+    let answer_count = write_records(&mut writer, config, address, answers)?;
+    let additional_count = write_records(&mut writer, config, address, additional)?;
+    writer.buffer[counts_at..counts_at + 2].copy_from_slice(&answer_count.to_be_bytes());
+    writer.buffer[counts_at + 4..counts_at + 6].copy_from_slice(&additional_count.to_be_bytes());
+    Some(writer.len)
+}
+
+/// Writes the records in `records`, returning how many that was
+fn write_records(
+    writer: &mut Writer<'_>,
+    config: &MdnsConfig,
+    address: [u8; 4],
+    records: Records,
+) -> Option<u16> {
+    let mut count = 0;
+    let host = Name {
+        instance: None,
+        parts: &[config.hostname, "local"],
+    };
+    if records.contains(Records::A) {
+        writer.record(&host, TYPE_A, CACHE_FLUSH, HOST_TTL_SECS, |w| {
+            w.bytes(&address)
+        })?;
+        count += 1;
+    }
+    for (index, service) in config.services.iter().take(MAX_SERVICES).enumerate() {
+        let bit = |record| Records::service(index, record);
+        let service_name = Name {
+            instance: None,
+            parts: &[service.service, "local"],
+        };
+        let instance_name = Name {
+            instance: Some(service.instance),
+            parts: &[service.service, "local"],
+        };
+        if records.contains(bit(Records::SERVICES_PTR)) {
+            let services = Name {
+                instance: None,
+                parts: &[SERVICES_NAME],
+            };
+            writer.record(&services, TYPE_PTR, 0, SERVICE_TTL_SECS, |w| {
+                w.name(&service_name)
+            })?;
+            count += 1;
+        }
+        if records.contains(bit(Records::PTR)) {
+            writer.record(&service_name, TYPE_PTR, 0, SERVICE_TTL_SECS, |w| {
+                w.name(&instance_name)
+            })?;
+            count += 1;
+        }
+        if records.contains(bit(Records::SRV)) {
+            writer.record(&instance_name, TYPE_SRV, CACHE_FLUSH, HOST_TTL_SECS, |w| {
+                // Priority and weight don't matter with one host
+                w.u16(0)?;
+                w.u16(0)?;
+                w.u16(service.port)?;
+                w.name(&host)
+            })?;
+            count += 1;
+        }
+        if records.contains(bit(Records::TXT)) {
+            writer.record(
+                &instance_name,
+                TYPE_TXT,
+                CACHE_FLUSH,
+                SERVICE_TTL_SECS,
+                |w| {
+                    // An empty TXT record still needs one empty string
+                    if service.txt.is_empty() {
+                        return w.bytes(&[0]);
+                    }
+                    for entry in service.txt {
+                        w.bytes(&[entry.len().min(255) as u8])?;
+                        w.bytes(&entry.as_bytes()[..entry.len().min(255)])?;
+                    }
+                    Some(())
+                },
+            )?;
+            count += 1;
+        }
+    }
+    Some(count)
+}
+
+/// A name to write. Dots split the parts into labels, but not the instance name of a service
+struct Name<'n> {
+    instance: Option<&'n str>,
+    parts: &'n [&'n str],
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn name(&mut self, name: &Name<'_>) -> Option<()> {
+        if let Some(instance) = name.instance {
+            self.label(instance)?;
+        }
+        for part in name.parts {
+            for label in part.split('.') {
+                self.label(label)?;
+            }
+        }
+        self.bytes(&[0])
+    }
+
+    fn label(&mut self, label: &str) -> Option<()> {
+        let len = label.len().min(63);
+        self.bytes(&[len as u8])?;
+        self.bytes(&label.as_bytes()[..len])
+    }
+
+    fn record(
+        &mut self,
+        name: &Name<'_>,
+        record_type: u16,
+        class_flags: u16,
+        ttl: u32,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(record_type)?;
+        self.u16(CLASS_IN | class_flags)?;
+        self.bytes(&ttl.to_be_bytes())?;
+        let length_at = self.len;
+        self.u16(0)?;
+        data(self)?;
+        let data_len = (self.len - length_at - 2) as u16;
+        self.buffer[length_at..length_at + 2].copy_from_slice(&data_len.to_be_bytes());
+        Some(())
+    }
+}
+
+/// The dotted name at `offset` and where the data after it starts, following compression
+/// pointers
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String<256>, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Pointers only ever go backwards in sane packets, this stops loops in the rest
+    for _ in 0..32 {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some((name, end.unwrap_or(offset + 1))),
+            len if len & 0xC0 == 0xC0 => {
+                let pointer = (len & 0x3F) << 8 | *packet.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            len if len & 0xC0 == 0 => {
+                let label = core::str::from_utf8(packet.get(offset + 1..offset + 1 + len)?).ok()?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                name.push_str(label).ok()?;
+                offset += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
@@ -1,35 +1,42 @@
+use crate::auth::PasswordHash;
 use crate::FLASH_SIZE;
 use defmt::*;
 use embassy_rp::flash::{Async, ERASE_SIZE};
 use embassy_rp::peripherals::FLASH;
-use heapless::String;
+use heapless::{String, Vec};
 use postcard::{from_bytes, to_slice};
 use serde::{Deserialize, Serialize};
 use {defmt_rtt as _, panic_probe as _};
 
+/// Just past the end of the firmware's flash region in `memory.x`
 const ADDR_OFFSET: u32 = 0x100000;
 const SAVE_OFFSET: u32 = 0x00;
+/// Written before the postcard bytes. Saves from before there was a version start with the
+/// `clear_on_boot` bool, so they start with 0 or 1
+const SAVE_VERSION: u8 = 3;
+pub const MAX_SAVED_NETWORKS: usize = 8;
 
 pub fn save_postcard_to_flash(
     flash: &mut embassy_rp::flash::Flash<'_, FLASH, Async, FLASH_SIZE>,
     data: &Save,
 ) -> Result<(), &'static str> {
     let mut write_buf = [0u8; ERASE_SIZE];
-    let written = to_slice(data, &mut write_buf).map_err(|_| "Serialization error")?;
-
-    if written.len() > ERASE_SIZE {
-        return Err("Data too large for flash sector");
-    }
+    write_buf[0] = SAVE_VERSION;
+    let written = to_slice(data, &mut write_buf[1..])
+        .map_err(|_| "Serialization error")?
+        .len()
+        + 1;
+    let written = &write_buf[..written];
 
     erase_save_flash(flash);
 
     // buf[..written.len()].copy_from_slice(&written);
-    let save_as_str = core::str::from_utf8(&written);
+    let save_as_str = core::str::from_utf8(written);
     if save_as_str.is_ok() {
         info!("saving as str: {:?}", save_as_str.unwrap());
     }
     flash
-        .blocking_write(ADDR_OFFSET + SAVE_OFFSET, &written)
+        .blocking_write(ADDR_OFFSET + SAVE_OFFSET, written)
         .map_err(|_| "Write error")?;
 
     Ok(())
@@ -51,7 +58,15 @@ pub fn read_postcard_from_flash(
     if save_as_str.is_ok() {
         info!("Reading as str: {:?}", save_as_str.unwrap());
     }
-    let data = from_bytes::<Save>(&buf);
+    let data = match buf[0] {
+        SAVE_VERSION => from_bytes::<Save>(&buf[1..]),
+        2 => from_bytes::<SaveV2>(&buf[1..]).map(Save::from),
+        // The sector is erased before every write, so where an original save ends the next
+        // byte is 0xFF, which isn't a valid `admin_password` and fails the newer layout
+        _ => from_bytes::<SaveV1>(&buf)
+            .map(Save::from)
+            .or_else(|_| from_bytes::<SaveV0>(&buf).map(Save::from)),
+    };
     match data {
         Ok(data) => {
             debug!("Save Data: {:?}", data);
@@ -75,9 +90,140 @@ pub fn erase_save_flash(flash: &mut embassy_rp::flash::Flash<'_, FLASH, Async, F
         .unwrap();
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, defmt::Format)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug, Eq, PartialEq, defmt::Format)]
 pub struct Save {
-    pub clear_on_boot: bool,
-    pub wifi_ssid: String<32>,
-    pub wifi_password: String<32>,
+    /// Tried in this order, the first is the highest priority
+    pub networks: Vec<SavedNetwork, MAX_SAVED_NETWORKS>,
+    /// SSID of the network joined most recently
+    pub last_connected: Option<String<32>>,
+    /// None until the password is set on first boot
+    pub admin_password: Option<PasswordHash>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, Eq, PartialEq, defmt::Format)]
+pub struct SavedNetwork {
+    pub ssid: String<32>,
+    pub password: String<32>,
+}
+
+impl Save {
+    /// Adds the network or changes its password, then moves it to `priority`. The end of the
+    /// list for new networks when there's no priority
+    pub fn add_network(
+        &mut self,
+        network: SavedNetwork,
+        priority: Option<usize>,
+    ) -> Result<(), &'static str> {
+        let existing = self.network_index(&network.ssid);
+        if existing.is_none() && self.networks.is_full() {
+            return Err("Too many saved networks, remove one first");
+        }
+        if let Some(index) = existing {
+            self.networks.remove(index);
+        }
+        let len = self.networks.len();
+        let index = priority.or(existing).unwrap_or(len).min(len);
+        // There's room, either there was before or one was just removed
+        let _ = self.networks.insert(index, network);
+        Ok(())
+    }
+
+    pub fn remove_network(&mut self, ssid: &str) -> Result<(), &'static str> {
+        let index = self
+            .network_index(ssid)
+            .ok_or("No saved network with that SSID")?;
+        self.networks.remove(index);
+        Ok(())
+    }
+
+    /// Moves the listed networks to the front in the order given, the rest keep their order
+    /// after them
+    pub fn reorder_networks(&mut self, ssids: &[String<32>]) -> Result<(), &'static str> {
+        let mut networks = Vec::new();
+        for ssid in ssids {
+            let index = self
+                .network_index(ssid)
+                .ok_or("No saved network with that SSID")?;
+            let _ = networks.push(self.networks.remove(index));
+        }
+        let rest = core::mem::replace(&mut self.networks, networks);
+        for network in rest {
+            let _ = self.networks.push(network);
+        }
+        Ok(())
+    }
+
+    fn network_index(&self, ssid: &str) -> Option<usize> {
+        self.networks
+            .iter()
+            .position(|network| network.ssid == ssid)
+    }
+}
+
+/// The original layout, from before there was an admin password
+#[derive(Deserialize)]
+struct SaveV0 {
+    _clear_on_boot: bool,
+    wifi_ssid: String<32>,
+    wifi_password: String<32>,
+}
+
+impl From<SaveV0> for Save {
+    fn from(old: SaveV0) -> Self {
+        SaveV1 {
+            _clear_on_boot: false,
+            wifi_ssid: old.wifi_ssid,
+            wifi_password: old.wifi_password,
+            admin_password: None,
+        }
+        .into()
+    }
+}
+
+/// The layout before several networks could be saved
+#[derive(Deserialize)]
+struct SaveV1 {
+    _clear_on_boot: bool,
+    wifi_ssid: String<32>,
+    wifi_password: String<32>,
+    admin_password: Option<PasswordHash>,
+}
+
+impl From<SaveV1> for Save {
+    fn from(old: SaveV1) -> Self {
+        let mut networks = Vec::new();
+        if !old.wifi_ssid.is_empty() {
+            let _ = networks.push(SavedNetwork {
+                ssid: old.wifi_ssid,
+                password: old.wifi_password,
+            });
+        }
+        Self {
+            networks,
+            last_connected: None,
+            admin_password: old.admin_password,
+        }
+    }
+}
+
+/// The layout from when boot wrote a placeholder with `clear_on_boot` set, to wipe the
+/// settings if joining crashed the device
+#[derive(Deserialize)]
+struct SaveV2 {
+    /// Only ever set in that placeholder, which has no networks to keep. It's ignored in
+    /// this and the older layouts
+    _clear_on_boot: bool,
+    networks: Vec<SavedNetwork, MAX_SAVED_NETWORKS>,
+    last_connected: Option<String<32>>,
+    admin_password: Option<PasswordHash>,
+}
+
+impl From<SaveV2> for Save {
+    fn from(old: SaveV2) -> Self {
+        Self {
+            networks: old.networks,
+            last_connected: old.last_connected,
+            admin_password: old.admin_password,
+        }
+    }
 }
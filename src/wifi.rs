@@ -0,0 +1,93 @@
+//! Finding Wi-Fi networks with the cyw43 chip and picking which saved one to join
+use core::cmp::Reverse;
+use cyw43::{BssInfo, Control, ScanOptions};
+use defmt::*;
+use embassy_time::{with_timeout, Duration};
+use heapless::{String, Vec};
+
+use crate::api::{WifiNetwork, WifiSecurity};
+use crate::save::{SavedNetwork, MAX_SAVED_NETWORKS};
+
+/// The strongest networks are kept when there are more
+pub const MAX_SCAN_RESULTS: usize = 16;
+/// A full scan of every channel takes a few seconds, this only catches a stuck one
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Set in a network's capabilities when it needs a key. The beacon has the details of
+/// which kind but the chip doesn't hand those over
+const CAPABILITY_PRIVACY: u16 = 0x0010;
+
+/// Every network in range, strongest first. Works both when joined to a network and when
+/// running the setup access point
+pub async fn scan(control: &mut Control<'_>) -> Vec<WifiNetwork, MAX_SCAN_RESULTS> {
+    let mut networks = Vec::new();
+    let mut scanner = control.scan(ScanOptions::default()).await;
+    let scanned = with_timeout(SCAN_TIMEOUT, async {
+        while let Some(bss) = scanner.next().await {
+            add_network(&mut networks, &bss);
+        }
+    })
+    .await;
+    if scanned.is_err() {
+        warn!("Wi-Fi scan timed out, returning what was found");
+    }
+    networks.sort_unstable_by_key(|network| Reverse(network.rssi));
+    networks
+}
+
+/// Access points sharing an SSID are one network to whoever picks from the list, so only
+/// the strongest is kept. Hidden networks have nothing to pick and are skipped
+fn add_network(networks: &mut Vec<WifiNetwork, MAX_SCAN_RESULTS>, bss: &BssInfo) {
+    // Copied out since the struct is packed
+    let (ssid, ssid_len, rssi) = (bss.ssid, bss.ssid_len, bss.rssi);
+    let (chanspec, capability) = (bss.chanspec, bss.capability);
+    let Some(ssid) = ssid.get(..ssid_len as usize) else {
+        return;
+    };
+    let Ok(ssid) = core::str::from_utf8(ssid) else {
+        return;
+    };
+    if ssid.is_empty() || ssid.bytes().all(|b| b == 0) {
+        return;
+    }
+    let network = WifiNetwork {
+        ssid: String::try_from(ssid).unwrap_or_default(),
+        rssi,
+        // The low byte is the channel, the rest is band and width
+        channel: (chanspec & 0xff) as u8,
+        security: if capability & CAPABILITY_PRIVACY != 0 {
+            WifiSecurity::Secured
+        } else {
+            WifiSecurity::Open
+        },
+    };
+
+    if let Some(known) = networks.iter_mut().find(|known| known.ssid == network.ssid) {
+        if network.rssi > known.rssi {
+            *known = network;
+        }
+        return;
+    }
+    if let Err(network) = networks.push(network) {
+        if let Some(weakest) = networks.iter_mut().min_by_key(|known| known.rssi) {
+            if network.rssi > weakest.rssi {
+                *weakest = network;
+            }
+        }
+    }
+}
+
+/// Saved networks in the order to try them. The ones the scan saw go first by priority, then
+/// the rest since hidden networks never show up in a scan
+pub fn join_order<'a>(
+    saved: &'a [SavedNetwork],
+    in_range: &[WifiNetwork],
+) -> Vec<&'a SavedNetwork, MAX_SAVED_NETWORKS> {
+    let seen = |network: &SavedNetwork| in_range.iter().any(|found| found.ssid == network.ssid);
+    let mut order = Vec::new();
+    for in_scan in [true, false] {
+        for network in saved.iter().filter(|&network| seen(network) == in_scan) {
+            let _ = order.push(network);
+        }
+    }
+    order
+}
@@ -0,0 +1,256 @@
+//! Keeps the device reachable after boot. Joins the saved networks, watches the link and
+//! the DHCP lease, rejoins with a growing wait when either goes away and brings up the setup
+//! access point once the network has been gone too long. From the access point it keeps
+//! looking for a saved network to go back to, so none of this needs a restart
+use core::cell::RefCell;
+use cyw43::JoinOptions;
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_net::{ConfigV4, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::String;
+
+use crate::events::{self, Event};
+use crate::save::SavedNetwork;
+use crate::{captive_portal, dhcp_server, wifi, WebsiteHandler, AP_DHCP};
+
+const AP_SSID: &str = "Picosapien";
+const AP_CHANNEL: u8 = 5;
+/// How long the access point stays up after a network is saved, so the reply to the save
+/// reaches the phone before its Wi-Fi goes away
+const SAVE_REPLY_GRACE: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy)]
+pub struct SupervisorConfig {
+    /// How often the link and DHCP lease are checked while connected
+    pub check_interval: Duration,
+    /// How long the network can be gone before the setup access point comes up
+    pub ap_fallback_after: Duration,
+    /// Wait after the first round of failed joins, doubled after every round up to `max_backoff`
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long a joined network has to hand out an address
+    pub dhcp_timeout: Duration,
+    /// How often the access point scans for a saved network to go back to
+    pub ap_rescan_interval: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum WifiMode {
+    /// Joining, or rejoining after losing the network
+    Connecting,
+    Connected,
+    AccessPoint,
+}
+
+#[derive(Clone)]
+pub struct WifiState {
+    pub mode: WifiMode,
+    /// The joined network, empty when not connected
+    pub ssid: String<32>,
+}
+
+impl WifiState {
+    pub fn connected(&self) -> bool {
+        self.mode == WifiMode::Connected
+    }
+
+    pub fn access_point_mode(&self) -> bool {
+        self.mode == WifiMode::AccessPoint
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<WifiState>> =
+    Mutex::new(RefCell::new(WifiState {
+        mode: WifiMode::Connecting,
+        ssid: String::new(),
+    }));
+/// Wakes the access point to try a network that was just saved
+static NETWORKS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn state() -> WifiState {
+    STATE.lock(|state| state.borrow().clone())
+}
+
+/// Call after saving a network, the access point tries it straight away instead of at the
+/// next scan
+pub fn networks_changed() {
+    NETWORKS_CHANGED.signal(());
+}
+
+fn set_state(mode: WifiMode, ssid: String<32>) {
+    info!("Wi-Fi is now {}: {}", mode, ssid);
+    STATE.lock(|state| {
+        *state.borrow_mut() = WifiState {
+            mode,
+            ssid: ssid.clone(),
+        }
+    });
+    events::publish(Event::WifiStatus {
+        connected: mode == WifiMode::Connected,
+        access_point_mode: mode == WifiMode::AccessPoint,
+        wifi_ssid: ssid,
+    });
+}
+
+/// Runs for as long as the device does, alongside the web server that shares the handler
+pub async fn supervise(handler: &WebsiteHandler, stack: Stack<'static>, config: SupervisorConfig) {
+    let mut window = config.ap_fallback_after;
+    loop {
+        set_state(WifiMode::Connecting, String::new());
+        match connect(handler, stack, &config, window).await {
+            Some(ssid) => {
+                if handler.saved.borrow().last_connected.as_ref() != Some(&ssid) {
+                    let last_connected = ssid.clone();
+                    let update = handler.update_save(|save| {
+                        save.last_connected = Some(last_connected);
+                        Ok(())
+                    });
+                    if update.await.is_err() {
+                        warn!("Could not save the last connected network");
+                    }
+                }
+                set_state(WifiMode::Connected, ssid);
+                wait_for_outage(stack, &config).await;
+                warn!("Lost the Wi-Fi network, rejoining");
+                window = config.ap_fallback_after;
+            }
+            None => {
+                run_access_point(handler, stack, &config).await;
+                // The access point only gives up its clients for one round of joins
+                window = Duration::from_secs(0);
+            }
+        }
+    }
+}
+
+/// Tries the saved networks in rounds until one is joined and hands out an address, or
+/// until `window` has passed. The SSID joined, None when it's time for the access point
+async fn connect(
+    handler: &WebsiteHandler,
+    stack: Stack<'static>,
+    config: &SupervisorConfig,
+    window: Duration,
+) -> Option<String<32>> {
+    let started = Instant::now();
+    let mut backoff = config.min_backoff;
+    loop {
+        let networks = handler.saved.borrow().networks.clone();
+        if networks.is_empty() {
+            return None;
+        }
+        let in_range = wifi::scan(&mut *handler.control.lock().await).await;
+        for network in wifi::join_order(&networks, &in_range) {
+            if join(handler, stack, network, config).await {
+                return Some(network.ssid.clone());
+            }
+        }
+
+        if started.elapsed() + backoff > window {
+            return None;
+        }
+        info!(
+            "No saved network could be joined, trying again in {} s",
+            backoff.as_secs()
+        );
+        // A network saved meanwhile is worth trying right away
+        select(Timer::after(backoff), NETWORKS_CHANGED.wait()).await;
+        backoff = (backoff * 2).min(config.max_backoff);
+    }
+}
+
+async fn join(
+    handler: &WebsiteHandler,
+    stack: Stack<'static>,
+    network: &SavedNetwork,
+    config: &SupervisorConfig,
+) -> bool {
+    debug!("Attempting to connect to wifi: {}", network.ssid);
+    let joined = handler
+        .control
+        .lock()
+        .await
+        .join(
+            network.ssid.as_str(),
+            JoinOptions::new(network.password.as_bytes()),
+        )
+        .await;
+    if let Err(err) = joined {
+        warn!("join {} failed with status={}", network.ssid, err.status);
+        return false;
+    }
+    // A network that lets the device on but never hands out an address is no use either
+    let dhcp = with_timeout(config.dhcp_timeout, async {
+        while !stack.is_config_up() {
+            Timer::after_millis(100).await;
+        }
+    });
+    if dhcp.await.is_err() {
+        warn!("{} gave no DHCP lease", network.ssid);
+        handler.control.lock().await.leave().await;
+        return false;
+    }
+    info!("join {} successful", network.ssid);
+    true
+}
+
+/// Returns once the link or the DHCP lease is gone
+async fn wait_for_outage(stack: Stack<'static>, config: &SupervisorConfig) {
+    while stack.is_link_up() && stack.is_config_up() {
+        Timer::after(config.check_interval).await;
+    }
+}
+
+/// Serves the setup access point until a saved network shows up in a scan or one is saved
+async fn run_access_point(
+    handler: &WebsiteHandler,
+    stack: Stack<'static>,
+    config: &SupervisorConfig,
+) {
+    info!("Could not connect to save connection bringing up AP");
+    {
+        let mut control = handler.control.lock().await;
+        control.leave().await;
+        // The device is the network's router, its own DHCP server hands out the addresses
+        let [a, b, c, d] = captive_portal::AP_ADDRESS;
+        stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), AP_DHCP.prefix_len),
+            dns_servers: heapless::Vec::new(),
+            gateway: None,
+        }));
+        control.start_ap_open(AP_SSID, AP_CHANNEL).await;
+    }
+    set_state(WifiMode::AccessPoint, String::new());
+    NETWORKS_CHANGED.reset();
+
+    loop {
+        let saved_now = select(
+            Timer::after(config.ap_rescan_interval),
+            NETWORKS_CHANGED.wait(),
+        )
+        .await;
+        let networks = handler.saved.borrow().networks.clone();
+        if networks.is_empty() {
+            continue;
+        }
+        // A network that was just saved may be hidden, so it's tried without a scan
+        if matches!(saved_now, Either::Second(())) {
+            Timer::after(SAVE_REPLY_GRACE).await;
+            break;
+        }
+        let in_range = wifi::scan(&mut *handler.control.lock().await).await;
+        let found = networks
+            .iter()
+            .any(|network| in_range.iter().any(|found| found.ssid == network.ssid));
+        if found {
+            break;
+        }
+    }
+
+    info!("Leaving the access point to join a saved network");
+    handler.control.lock().await.close_ap().await;
+    dhcp_server::clear_leases();
+    stack.set_config_v4(ConfigV4::Dhcp(Default::default()));
+}